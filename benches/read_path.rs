@@ -0,0 +1,133 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use lsm_engine::{LSMBuilder, LSMEngine};
+
+const SEGMENT_SIZE: usize = 500;
+const VALUE_SIZES: [usize; 2] = [16, 1024];
+const SEGMENT_COUNTS: [usize; 3] = [1, 8, 32];
+
+fn key(i: usize) -> String {
+    format!("key{:08}", i)
+}
+
+/// An engine holding `segments` full segments, plus a few entries left in the memtable.
+/// Only public APIs are used, so the benches also check that the API they rely on still compiles.
+fn fixture(segments: usize, value_size: usize) -> (LSMEngine, usize) {
+    let mut lsm = LSMBuilder::new()
+        .inmemory_capacity(SEGMENT_SIZE)
+        .segment_size(SEGMENT_SIZE)
+        .build()
+        .unwrap();
+    let keys = segments * SEGMENT_SIZE + 1;
+    let value = "v".repeat(value_size);
+    for i in 0..keys {
+        lsm.write(key(i), value.as_str()).unwrap();
+    }
+    (lsm, keys)
+}
+
+fn point_reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("point_read");
+    for &value_size in VALUE_SIZES.iter() {
+        for &segments in SEGMENT_COUNTS.iter() {
+            let (mut lsm, keys) = fixture(segments, value_size);
+            let param = format!("{}seg/{}B", segments, value_size);
+            //the last write always stays in the memtable
+            let in_memtable = key(keys - 1);
+            let in_newest_segment = key(keys - 2);
+            let in_oldest_segment = key(0);
+            let missing = format!("{}-missing", key(keys / 2));
+
+            group.bench_with_input(BenchmarkId::new("memtable_hit", &param), &in_memtable, |b, k| {
+                b.iter(|| lsm.read(k).unwrap())
+            });
+            group.bench_with_input(BenchmarkId::new("newest_segment_hit", &param), &in_newest_segment, |b, k| {
+                b.iter(|| lsm.read(k).unwrap())
+            });
+            group.bench_with_input(BenchmarkId::new("oldest_segment_hit", &param), &in_oldest_segment, |b, k| {
+                b.iter(|| lsm.read(k).unwrap())
+            });
+            group.bench_with_input(BenchmarkId::new("miss", &param), &missing, |b, k| {
+                b.iter(|| lsm.contains(k).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+fn scans(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scan");
+    for &value_size in VALUE_SIZES.iter() {
+        let (mut lsm, keys) = fixture(*SEGMENT_COUNTS.last().unwrap(), value_size);
+        for &len in [10, 1000].iter() {
+            let start = key(keys / 2);
+            let end = key(keys / 2 + len);
+            group.throughput(Throughput::Elements(len as u64));
+            group.bench_with_input(BenchmarkId::new(format!("{}_keys", len), format!("{}B", value_size)), &len, |b, _| {
+                b.iter(|| lsm.range(start.as_str()..end.as_str()).unwrap().count())
+            });
+        }
+    }
+    group.finish();
+}
+
+fn write_burst(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_burst");
+    group.sample_size(10);
+    for &value_size in VALUE_SIZES.iter() {
+        let value = "v".repeat(value_size);
+        group.throughput(Throughput::Elements(SEGMENT_SIZE as u64));
+        //one memtable's worth of writes, so every iteration ends in a flush and merge
+        group.bench_function(BenchmarkId::new("flush_and_merge", format!("{}B", value_size)), |b| {
+            let (mut lsm, mut next) = fixture(4, value_size);
+            b.iter(|| {
+                for _ in 0..SEGMENT_SIZE {
+                    lsm.write(key(next), value.as_str()).unwrap();
+                    next += 1;
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Writes and deletes look their key up to keep `len` exact. Overwrites find it in a segment,
+/// while new keys are mostly ruled out by the bloom filters.
+fn write_lookups(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_lookup");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(SEGMENT_SIZE as u64));
+    for &segments in SEGMENT_COUNTS.iter() {
+        let param = format!("{}seg", segments);
+        group.bench_function(BenchmarkId::new("new_key", &param), |b| {
+            let (mut lsm, mut next) = fixture(segments, 16);
+            b.iter(|| {
+                for _ in 0..SEGMENT_SIZE {
+                    lsm.write(key(next), "new").unwrap();
+                    next += 1;
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("overwrite", &param), |b| {
+            let (mut lsm, keys) = fixture(segments, 16);
+            let mut next = 0;
+            b.iter(|| {
+                for _ in 0..SEGMENT_SIZE {
+                    lsm.write(key(next % keys), "new").unwrap();
+                    next += 1;
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("delete_many", &param), |b| {
+            let (mut lsm, keys) = fixture(segments, 16);
+            let mut next = 0;
+            b.iter(|| {
+                lsm.delete_many((next..next + SEGMENT_SIZE).map(|i| key(i % keys))).unwrap();
+                next += SEGMENT_SIZE;
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, point_reads, scans, write_burst, write_lookups);
+criterion_main!(benches);
@@ -0,0 +1,132 @@
+use std::cmp::Ordering;
+use std::io::{Seek, SeekFrom};
+use crate::{LSMEngine, LSMBuilder, Result, Error, RecoveryReport};
+use crate::kv::KvError;
+use crate::wal::StopReason;
+
+
+/// A key whose newest value in the WAL is not what the store returns. `None` means absent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub key: String,
+    pub wal: Option<String>,
+    pub store: Option<String>,
+}
+
+/// Outcome of `LSMEngine::audit_consistency`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsistencyReport {
+    pub wal_records: u64,
+    /// distinct keys live in the WAL or the store
+    pub keys_checked: u64,
+    /// in key order
+    pub divergences: Vec<Divergence>,
+    /// how reading the WAL ended. Records past a torn or corrupt one are not audited
+    pub wal_stop_reason: StopReason,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.divergences.is_empty()
+    }
+}
+
+impl LSMEngine {
+    /// Replays the WAL into a scratch engine and compares it key by key with what this engine returns,
+    /// to check after an incident that no acknowledged write was lost. The scratch engine spills to
+    /// its own segments like replay does, so memory stays bounded by `inmemory_capacity` however
+    /// long the WAL is. Nothing in this engine is modified, the WAL included.
+    pub fn audit_consistency(&mut self) -> Result<ConsistencyReport> {
+        let mut model = LSMBuilder::new()
+            .inmemory_capacity(self.memtable.capacity())
+            .segment_size(self.segment_size)
+            .build()?;
+        let wal = self.wal.as_mut().ok_or(Error::WalNotConfigured)?;
+        let id = wal.id.clone();
+        let mut wal_records = 0;
+        let mut memtable_bytes = 0;
+        let mut replay = RecoveryReport::default();
+        let mut records = wal.records(self.max_record_bytes)?;
+        let replayed = records.by_ref().try_for_each(|entry| {
+            let entry = entry.map_err(|source| Error::WalCorrupted { wal: id.clone(), source })?;
+            wal_records += 1;
+            entry.into_writes().into_iter().try_for_each(|kv| model.replay_write(kv, &mut memtable_bytes, &mut replay))
+        });
+        let wal_stop_reason = records.stop_reason().unwrap_or_default();
+        //new records must keep going to the end of the log
+        wal.file.seek(SeekFrom::End(0)).map_err(KvError::from)?;
+        replayed?;
+        if replay.segments_flushed > 0 {
+            model.merge_segments()?;
+        }
+
+        let mut report = ConsistencyReport { wal_records, keys_checked: 0, divergences: vec![], wal_stop_reason };
+        let mut logged_scan = model.range::<String, _>(..)?;
+        let mut stored_scan = self.range::<String, _>(..)?;
+        let mut logged = logged_scan.by_ref().peekable();
+        let mut stored = stored_scan.by_ref().peekable();
+        loop {
+            let order = match (logged.peek(), stored.peek()) {
+                (None, None) => break,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((logged, _)), Some((stored, _))) => logged.cmp(stored),
+            };
+            let divergence = match order {
+                Ordering::Less => logged.next().map(|(key, value)| Divergence { key, wal: Some(value), store: None }),
+                Ordering::Greater => stored.next().map(|(key, value)| Divergence { key, wal: None, store: Some(value) }),
+                Ordering::Equal => {
+                    let ((key, logged), (_, stored)) = (logged.next().unwrap(), stored.next().unwrap());
+                    Some(Divergence { key, wal: Some(logged), store: Some(stored) })
+                        .filter(|divergence| divergence.wal != divergence.store)
+                }
+            };
+            report.keys_checked += 1;
+            report.divergences.extend(divergence);
+        }
+        logged_scan.finish()?;
+        stored_scan.finish()?;
+        Ok(report)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{LSMBuilder, Error, Divergence};
+
+    #[test]
+    fn test_audit_pinpoints_unlogged_writes() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(3).segment_size(2).build()?;
+        for i in 0..10 {
+            lsm.write(format!("k{}", i), "v")?;
+        }
+        lsm.delete("k3")?;
+        let report = lsm.audit_consistency()?;
+        assert!(report.is_consistent());
+        assert_eq!(report.wal_records, 11);
+        assert_eq!(report.keys_checked, 9);
+
+        //bypass the WAL for a single write
+        let wal = lsm.wal.take();
+        lsm.write("k5", "unlogged")?;
+        lsm.wal = wal;
+        lsm.write("k10", "v")?;
+
+        let report = lsm.audit_consistency()?;
+        assert_eq!(report.divergences, vec![Divergence { key: "k5".to_owned(), wal: Some("v".to_owned()), store: Some("unlogged".to_owned()) }]);
+        assert_eq!(report.keys_checked, 10);
+        //the audit left the WAL appendable
+        lsm.write("k11", "v")?;
+        drop(lsm);
+        let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+        assert_eq!(lsm.read("k11")?, Some("v".to_owned()));
+        assert!(lsm.audit_consistency()?.is_consistent());
+
+        let mut no_wal = LSMBuilder::new().build()?;
+        assert!(matches!(no_wal.audit_consistency(), Err(Error::WalNotConfigured)));
+        Ok(())
+    }
+}
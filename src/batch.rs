@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+use crate::{LSMEngine, Result, check_user_key};
+use crate::kv::KVPair;
+
+
+/// Puts and deletes that `LSMEngine::write_batch` applies as one unit.
+/// Only the last operation on each key is kept.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WriteBatch {
+    //`None` marks a delete
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        self.writes.insert(key.into(), Some(value.into()));
+    }
+
+    pub fn delete<K: Into<String>>(&mut self, key: K) {
+        self.writes.insert(key.into(), None);
+    }
+
+    /// Number of distinct keys in the batch.
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+impl LSMEngine {
+    /// Applies every put and delete in `batch`. The whole batch goes into the WAL as a single record
+    /// before the memtable is touched, so recovery replays either all of it or none of it.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        for key in batch.writes.keys() {
+            check_user_key(key)?;
+        }
+        if let Some(tracer) = self.tracer.as_mut() {
+            for (key, value) in batch.writes.iter() {
+                match value {
+                    Some(value) => tracer.write(key, value.len())?,
+                    None => tracer.delete(key)?,
+                }
+            }
+        }
+        if let Some(wal) = self.wal.as_mut() {
+            wal.append_write_batch(batch.writes.iter().map(|(key, value)| (key.as_str(), value.as_deref())))?;
+        }
+        for (key, value) in batch.writes {
+            self.apply(KVPair { key, value, expires_at: None })?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{LSMBuilder, WriteBatch, Error};
+
+    #[test]
+    fn test_write_batch() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        {
+            let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(2).segment_size(2).build()?;
+            lsm.write("index:old", "user:1")?;
+            lsm.write("user:2", "bob")?;
+
+            let mut batch = WriteBatch::new();
+            batch.put("user:1", "alice");
+            batch.put("user:1", "alicia");
+            batch.delete("index:old");
+            batch.put("index:new", "user:1");
+            batch.delete("user:2");
+            batch.put("user:3", "carol");
+            assert_eq!(batch.len(), 5);
+            //the batch is bigger than the memtable, so it flushes part way through
+            lsm.write_batch(batch)?;
+
+            assert_eq!(lsm.read("user:1")?, Some("alicia".to_owned()));
+            assert_eq!(lsm.read("index:old")?, None);
+            assert_eq!(lsm.read("user:2")?, None);
+            assert_eq!(lsm.len(), 3);
+
+            let mut rejected = WriteBatch::new();
+            rejected.put("user:4", "dave");
+            rejected.put(format!("{}internal", crate::RESERVED_KEY_PREFIX), "v");
+            assert!(matches!(lsm.write_batch(rejected), Err(Error::ReservedKey { .. })));
+            assert_eq!(lsm.read("user:4")?, None);
+            lsm.write_batch(WriteBatch::new())?;
+        }
+        //2 puts, then the batch as one record
+        assert_eq!(std::fs::read_to_string(&path)?.lines().count(), 1 + 2 + 1);
+
+        let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+        assert_eq!(lsm.read("user:1")?, Some("alicia".to_owned()));
+        assert_eq!(lsm.read("index:new")?, Some("user:1".to_owned()));
+        assert_eq!(lsm.read("user:2")?, None);
+        assert_eq!(lsm.len(), 3);
+        assert_eq!(lsm.check_invariants(), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_torn_batch_is_dropped_whole() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        {
+            let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+            lsm.write("a", "1")?;
+            let mut batch = WriteBatch::new();
+            batch.put("b", "2");
+            batch.put("c", "3");
+            lsm.write_batch(batch)?;
+        }
+        let contents = std::fs::read_to_string(&path)?;
+        let cut = contents.trim_end().rfind(']').unwrap();
+        std::fs::write(&path, &contents[..cut])?;
+
+        let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+        assert_eq!(lsm.read("a")?, Some("1".to_owned()));
+        assert_eq!(lsm.read("b")?, None);
+        assert_eq!(lsm.read("c")?, None);
+        Ok(())
+    }
+}
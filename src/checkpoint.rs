@@ -0,0 +1,336 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use crate::{LSMEngine, Result, Error, FileId};
+use crate::sst::{Segment, SstError};
+use crate::{fsync, ttl};
+
+
+/// Name of the file describing a checkpoint. It is written last, so a directory without it
+/// holds an incomplete checkpoint.
+const CHECKPOINT_META: &str = "CHECKPOINT";
+const CHECKPOINT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct CheckpointMeta {
+    version: u32,
+    /// segment file names, in key order
+    segments: Vec<String>,
+    records: u64,
+    /// bumped by every checkpoint into the same directory, so each one writes its segments under new names
+    #[serde(default)]
+    generation: u64,
+}
+
+fn segment_name(generation: u64, index: usize) -> String {
+    format!("segment-{:06}-{:06}.jsonl", generation, index)
+}
+
+fn read_meta(path: &Path) -> Result<CheckpointMeta> {
+    let meta = fs::read(path).map_err(checkpoint_err(path))?;
+    Ok(serde_json::from_slice(&meta).map_err(crate::kv::KvError::from)?)
+}
+
+fn checkpoint_err(path: &Path) -> impl FnOnce(io::Error) -> Error + '_ {
+    move |source| Error::CheckpointIo { path: path.to_path_buf(), source }
+}
+
+impl LSMEngine {
+    /// Writes the live dataset into `dir` as sorted segment files plus a `CHECKPOINT` metadata file.
+    /// The memtable is flushed first; after that only the segments are read, so the checkpoint
+    /// doesn't need the WAL. Tombstones and expired entries are left out, while entries with a TTL
+    /// keep their expiry. Returns the number of records written.
+    ///
+    /// A checkpoint already in `dir` is replaced, but its files are never written over: the new
+    /// segments get new names, and the old ones are only removed once the new `CHECKPOINT` file is
+    /// in place. So `dir` holds a complete checkpoint at every point, and checkpointing an engine
+    /// into the directory it was restored from is safe.
+    pub fn checkpoint<P: AsRef<Path>>(&mut self, dir: P) -> Result<u64> {
+        let dir = dir.as_ref();
+        self.flush_and_merge()?;
+        fs::create_dir_all(dir).map_err(checkpoint_err(dir))?;
+        let path = dir.join(CHECKPOINT_META);
+        let previous = if path.exists() { Some(read_meta(&path)?) } else { None };
+        let generation = previous.as_ref().map(|meta| meta.generation + 1).unwrap_or(0);
+
+        let now = ttl::now_millis();
+        let segment_size = self.segment_size as u64;
+        let mut names = vec![];
+        let mut records: u64 = 0;
+        let mut output: Option<Segment> = None;
+        for segment in self.segments.iter_mut() {
+            for kv in segment.read_from_start()? {
+                let kv = kv?;
+                if kv.value.is_none() || ttl::is_expired(kv.expires_at, now) {
+                    continue;
+                }
+                if output.as_ref().map(|s| s.size() == segment_size).unwrap_or(true) {
+                    if let Some(full) = output.take() {
+                        full.sync()?;
+                    }
+                    let name = segment_name(generation, names.len());
+                    let path = dir.join(&name);
+                    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true)
+                        .open(&path).map_err(checkpoint_err(&path))?;
+                    output = Some(Segment::with_file(file, FileId::Path(path)));
+                    names.push(name);
+                }
+                output.as_mut().unwrap().write(kv)?;
+                records += 1;
+            }
+        }
+        if let Some(last) = output {
+            last.sync()?;
+        }
+
+        let meta = CheckpointMeta { version: CHECKPOINT_VERSION, segments: names, records, generation };
+        let staged = dir.join(format!("{}.tmp", CHECKPOINT_META));
+        let json = serde_json::to_vec(&meta).map_err(crate::kv::KvError::from)?;
+        fs::write(&staged, json).map_err(checkpoint_err(&staged))?;
+        fs::File::open(&staged).and_then(|f| f.sync_all()).map_err(checkpoint_err(&staged))?;
+        fs::rename(&staged, &path).map_err(checkpoint_err(&path))?;
+        fsync::sync_parent_dir(&path).map_err(checkpoint_err(&path))?;
+        //an engine restored from the old files keeps them open, so removing them doesn't pull its data away.
+        //A file left behind only wastes space
+        for name in previous.map(|meta| meta.segments).unwrap_or_default() {
+            let _ = fs::remove_file(dir.join(name));
+        }
+        Ok(records)
+    }
+
+    /// Replaces the engine's contents with a checkpoint written by `checkpoint`. Every segment file
+    /// is checked to be sorted, and the files to follow each other in key order, before anything
+    /// is replaced; the sparse index is then rebuilt with the engine's own `sparse_offset`.
+    ///
+    /// With a WAL, its records are replaced by the restored ones before the engine switches over,
+    /// so reopening the engine from its WAL brings back the restored dataset and nothing from before.
+    pub fn restore_from<P: AsRef<Path>>(&mut self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        let meta_path = dir.join(CHECKPOINT_META);
+        let meta = read_meta(&meta_path)?;
+        if meta.version > CHECKPOINT_VERSION {
+            return Err(Error::IncompatibleCheckpoint { path: meta_path, found: meta.version, supported: CHECKPOINT_VERSION });
+        }
+
+        let mut segments: Vec<Segment> = vec![];
+        for name in meta.segments.iter() {
+            let path = dir.join(name);
+            let file = fs::File::open(&path).map_err(checkpoint_err(&path))?;
+            let segment = Segment::load(file, FileId::Path(path), self.bloom_false_positive_rate, self.max_record_bytes)?;
+            let previous_last = segments.last().and_then(|s| s.key_range()).map(|(_, last)| last);
+            if let (Some(previous), Some((first, _))) = (previous_last, segment.key_range()) {
+                if previous >= first {
+                    return Err(SstError::UnsortedSegment {
+                        segment: segment.id().clone(),
+                        previous: previous.to_owned(),
+                        current: first.to_owned(),
+                    }.into());
+                }
+            }
+            segments.push(segment);
+        }
+
+        if let Some(wal) = self.wal.as_mut() {
+            let records = segments.iter_mut().map(|segment| segment.read_from_start()).collect::<std::result::Result<Vec<_>, _>>()?;
+            wal.replace_with(records.into_iter().flatten().map(|kv| kv.map_err(Error::from)))?;
+        }
+
+        self.clear_in_memory();
+        self.segments = segments;
+        self.rebuild_index_with(self.sparse_offset)?;
+        self.live_keys = self.count_keys_with_values()?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{LSMBuilder, Error, SstError, KvError};
+    use crate::kv::KVPair;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+    use super::{CheckpointMeta, CHECKPOINT_META};
+
+    fn checkpoint_files(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+        let meta: CheckpointMeta = serde_json::from_slice(&std::fs::read(dir.join(CHECKPOINT_META))?)?;
+        Ok(meta.segments.iter().map(|name| dir.join(name)).collect())
+    }
+
+    #[test]
+    fn test_checkpoint() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut lsm = LSMBuilder::new().inmemory_capacity(3).segment_size(2).build()?;
+        for i in 0..7 {
+            lsm.write(format!("k{}", i), format!("v{}", i))?;
+        }
+        lsm.delete("k2")?;
+        lsm.write("k5", "newer")?;
+        lsm.write_with_ttl("k7", "ttl", Duration::from_secs(3600))?;
+        assert!(!lsm.memtable.is_empty());
+
+        assert_eq!(lsm.checkpoint(dir.path())?, 7);
+        assert!(lsm.memtable.is_empty());
+        assert!(dir.path().join(CHECKPOINT_META).exists());
+        let files = checkpoint_files(dir.path())?;
+        assert_eq!(files.len(), 4);
+
+        let mut records = vec![];
+        for file in files {
+            let contents = std::fs::read_to_string(file)?;
+            for line in contents.lines() {
+                records.push(serde_json::from_str::<KVPair>(line)?);
+            }
+        }
+        let keys = records.iter().map(|kv| kv.key.clone()).collect::<Vec<_>>();
+        assert_eq!(keys, lsm.keys()?.collect::<Result<Vec<_>, _>>()?);
+        assert_eq!(records[4].value.as_deref(), Some("newer"));
+        assert_eq!(records[6].value.as_deref(), Some("ttl"));
+        assert!(records[6].expires_at.is_some());
+
+        //the engine keeps working, and a second checkpoint overwrites the first
+        lsm.write("k8", "v8")?;
+        assert_eq!(lsm.checkpoint(dir.path())?, 8);
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_from() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let expected = {
+            let mut lsm = LSMBuilder::new().inmemory_capacity(4).segment_size(3).build()?;
+            for i in 0..20 {
+                lsm.write(format!("k{:02}", i), format!("v{}", i))?;
+            }
+            lsm.delete_range("k05".."k08")?;
+            lsm.checkpoint(dir.path())?;
+            let expected = lsm.iter()?.collect::<Vec<_>>();
+            expected
+        };
+
+        let mut lsm = LSMBuilder::new().inmemory_capacity(4).segment_size(3).sparse_offset(2).build()?;
+        lsm.write("gone", "v")?;
+        lsm.restore_from(dir.path())?;
+        assert_eq!(lsm.iter()?.collect::<Vec<_>>(), expected);
+        assert_eq!(lsm.len(), 17);
+        assert_eq!(lsm.sparse_memory_index.len(), 9);
+        for (key, value) in expected.iter() {
+            assert_eq!(lsm.read(key)?.as_ref(), Some(value));
+        }
+        assert_eq!(lsm.read("gone")?, None);
+        assert_eq!(lsm.read("k06")?, None);
+
+        //writes keep going, merging the restored segments away
+        for i in 0..10 {
+            lsm.write(format!("k{:02}", i), "new")?;
+        }
+        assert_eq!(lsm.read("k06")?, Some("new".to_owned()));
+        assert_eq!(lsm.read("k19")?, Some("v19".to_owned()));
+        assert_eq!(lsm.len(), 20);
+        assert_eq!(lsm.check_invariants(), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint_into_restored_dir() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut lsm = LSMBuilder::new().inmemory_capacity(100).segment_size(100).build()?;
+        for i in 0..2000 {
+            lsm.write(format!("k{:04}", i), format!("v{}", i))?;
+        }
+        lsm.checkpoint(dir.path())?;
+        let first_files = checkpoint_files(dir.path())?;
+
+        //the restored engine reads from the very files it checkpoints over
+        let mut restored = LSMBuilder::new().inmemory_capacity(100).segment_size(100).build()?;
+        restored.restore_from(dir.path())?;
+        restored.write("k2000", "v2000")?;
+        assert_eq!(restored.checkpoint(dir.path())?, 2001);
+        assert!(first_files.iter().all(|file| !file.exists()));
+        assert_eq!(restored.read("k0000")?, Some("v0".to_owned()));
+        assert_eq!(restored.len(), 2001);
+
+        let mut again = LSMBuilder::new().build()?;
+        again.restore_from(dir.path())?;
+        assert_eq!(again.len(), 2001);
+        assert_eq!(again.iter()?.collect::<Vec<_>>(), restored.iter()?.collect::<Vec<_>>());
+        assert_eq!(again.check_invariants(), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_replaces_the_wal() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let checkpoint = dir.path().join("checkpoint");
+        let mut source = LSMBuilder::new().inmemory_capacity(4).segment_size(3).build()?;
+        for i in 0..10 {
+            source.write(format!("k{:02}", i), format!("v{}", i))?;
+        }
+        source.write_with_ttl("ttl", "v", Duration::from_secs(3600))?;
+        source.checkpoint(&checkpoint)?;
+
+        let path = dir.path().join("wal");
+        let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+        lsm.write("gone", "old")?;
+        lsm.write("k01", "old")?;
+        lsm.restore_from(&checkpoint)?;
+        lsm.write("after", "v")?;
+        drop(lsm);
+
+        let mut reopened = LSMBuilder::new().wal_path(&path).build()?;
+        assert_eq!(reopened.read("gone")?, None);
+        assert_eq!(reopened.read("k01")?, Some("v1".to_owned()));
+        assert_eq!(reopened.read("ttl")?, Some("v".to_owned()));
+        assert_eq!(reopened.read("after")?, Some("v".to_owned()));
+        assert_eq!(reopened.len(), 12);
+        assert_eq!(reopened.check_invariants(), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_rejects_unsorted_segments() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut lsm = LSMBuilder::new().inmemory_capacity(4).segment_size(3).build()?;
+        for i in 0..6 {
+            lsm.write(format!("k{}", i), "v")?;
+        }
+        lsm.checkpoint(dir.path())?;
+        let files = checkpoint_files(dir.path())?;
+
+        let mut restored = LSMBuilder::new().build()?;
+        restored.write("kept", "v")?;
+        //swapping the files keeps each one sorted, but they no longer follow each other
+        let first = std::fs::read(&files[0])?;
+        std::fs::write(&files[0], std::fs::read(&files[1])?)?;
+        std::fs::write(&files[1], &first)?;
+        assert!(matches!(restored.restore_from(dir.path()), Err(Error::SstError(SstError::UnsortedSegment { .. }))));
+
+        let contents = String::from_utf8(first)?;
+        let mut lines = contents.lines().collect::<Vec<_>>();
+        lines.swap(0, 1);
+        std::fs::write(&files[0], lines.join("\n") + "\n")?;
+        let err = restored.restore_from(dir.path()).unwrap_err();
+        assert_eq!(err.to_string(), format!("segment {} is not sorted: k0 comes after k1", files[0].display()));
+        assert_eq!(restored.read("kept")?, Some("v".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_refuses_oversized_records() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut lsm = LSMBuilder::new().build()?;
+        lsm.write("small", "v")?;
+        lsm.write("big", "v".repeat(500))?;
+        lsm.checkpoint(dir.path())?;
+
+        let mut restored = LSMBuilder::new().max_record_bytes(100).build()?;
+        assert!(matches!(restored.restore_from(dir.path()), Err(Error::SstError(SstError::KvError(KvError::RecordTooLarge { offset: 0, limit: 100 })))));
+        assert!(restored.is_empty());
+        let mut restored = LSMBuilder::new().max_record_bytes(600).build()?;
+        restored.restore_from(dir.path())?;
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored.check_invariants(), Ok(()));
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+use crate::{LSMEngine, Result, Error, FileId};
+use crate::sst::Segment;
+
+
+/// What a compaction would do, worked out from the segments' cached metadata without touching their files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionPlan {
+    /// the segments that would be merged, oldest first
+    pub inputs: Vec<FileId>,
+    pub bytes_to_read: u64,
+    pub estimated_bytes_to_write: u64,
+    pub records_to_read: u64,
+    /// tombstones in the inputs. Merging keeps them, so they carry over to the output
+    pub tombstones_kept: u64,
+    /// older versions of keys the merge is expected to drop. This is an upper bound,
+    /// since overlapping key ranges don't necessarily share keys
+    pub estimated_shadowed_dropped: u64,
+    /// projected number of records in each output segment
+    pub output_segments: Vec<u64>,
+}
+
+/// What `LSMEngine::compact` did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionReport {
+    pub segments_before: usize,
+    pub segments_after: usize,
+    pub records_before: u64,
+    pub records_after: u64,
+    /// older versions of keys that a newer version replaced
+    pub shadowed_dropped: u64,
+    /// entries whose TTL had run out
+    pub expired_dropped: u64,
+    /// tombstones in the output. Merging keeps them, so they are not counted as dropped
+    pub tombstones_kept: u64,
+}
+
+/// The current strategy merges every segment into one sorted run.
+fn choose_inputs(segments: &[Segment]) -> Vec<usize> {
+    (0..segments.len()).collect()
+}
+
+fn records(segment: &Segment) -> u64 {
+    segment.stats().live_records + segment.stats().tombstones
+}
+
+fn overlaps(a: &Segment, b: &Segment) -> bool {
+    match (a.key_range(), b.key_range()) {
+        (Some((a_first, a_last)), Some((b_first, b_last))) => a_first <= b_last && b_first <= a_last,
+        _ => false,
+    }
+}
+
+/// For every input, the records that can be shadowed are bounded both by its own size
+/// and by how many records newer inputs hold in an overlapping key range.
+fn estimate_shadowed(inputs: &[&Segment]) -> u64 {
+    inputs.iter()
+        .map(|older| {
+            let newer_overlapping: u64 = inputs.iter()
+                .filter(|newer| newer.timestamp() > older.timestamp() && overlaps(older, newer))
+                .map(|newer| records(newer))
+                .sum();
+            records(older).min(newer_overlapping)
+        })
+        .sum()
+}
+
+fn plan(segments: &[Segment], segment_size: usize) -> CompactionPlan {
+    let inputs: Vec<&Segment> = choose_inputs(segments).into_iter().map(|i| &segments[i]).collect();
+    let bytes_to_read: u64 = inputs.iter().map(|s| s.stats().total_bytes).sum();
+    let records_to_read: u64 = inputs.iter().map(|s| records(s)).sum();
+    let tombstones_kept = inputs.iter().map(|s| s.stats().tombstones).sum();
+    let estimated_shadowed_dropped = estimate_shadowed(&inputs);
+
+    let records_out = records_to_read - estimated_shadowed_dropped;
+    let estimated_bytes_to_write = if records_to_read == 0 {
+        0
+    } else {
+        (bytes_to_read as f64 * records_out as f64 / records_to_read as f64) as u64
+    };
+    let segment_size = segment_size as u64;
+    let output_segments = (0..records_out.div_ceil(segment_size))
+        .map(|i| segment_size.min(records_out - i * segment_size))
+        .collect();
+
+    CompactionPlan {
+        inputs: inputs.iter().map(|s| s.id().clone()).collect(),
+        bytes_to_read,
+        estimated_bytes_to_write,
+        records_to_read,
+        tombstones_kept,
+        estimated_shadowed_dropped,
+        output_segments,
+    }
+}
+
+impl LSMEngine {
+    /// Describes what a compaction would do right now, without running it.
+    /// Only cached segment metadata is read; no file IO happens.
+    pub fn plan_compaction(&self) -> CompactionPlan {
+        plan(&self.segments, self.segment_size)
+    }
+
+    /// Runs a compaction planned by `plan_compaction`. Fails with `Error::StaleCompactionPlan`
+    /// if the segments have changed since, e.g. because a flush happened in between.
+    pub fn compact_with_plan(&mut self, plan: CompactionPlan) -> Result<()> {
+        let current: Vec<&FileId> = choose_inputs(&self.segments).into_iter().map(|i| self.segments[i].id()).collect();
+        if current.len() != plan.inputs.len() || current.into_iter().zip(plan.inputs.iter()).any(|(a, b)| a != b) {
+            return Err(Error::StaleCompactionPlan);
+        }
+        if plan.inputs.is_empty() {
+            return Ok(());
+        }
+        self.merge_segments()
+    }
+
+    /// Merges the segments on demand rather than waiting for the next write at capacity, e.g. to
+    /// schedule compaction for off-peak hours. With fewer than two segments there is nothing to
+    /// merge, so nothing is rewritten. The memtable is left as it is.
+    pub fn compact(&mut self) -> Result<CompactionReport> {
+        let segments_before = self.segments.len();
+        let records_before: u64 = self.segments.iter().map(records).sum();
+        if segments_before > 1 {
+            self.merge_segments()?;
+        }
+        let records_after: u64 = self.segments.iter().map(records).sum();
+        let shadowed_dropped = if segments_before > 1 {
+            self.segments.iter().map(|s| s.stats().shadowed_dropped).sum()
+        } else {
+            0
+        };
+        Ok(CompactionReport {
+            segments_before,
+            segments_after: self.segments.len(),
+            records_before,
+            records_after,
+            shadowed_dropped,
+            expired_dropped: records_before - records_after - shadowed_dropped,
+            tombstones_kept: self.segments.iter().map(|s| s.stats().tombstones).sum(),
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{LSMBuilder, LSMEngine, Error};
+
+    fn flush_unmerged(lsm: &mut LSMEngine, keys: std::ops::Range<usize>, version: &str) -> Result<(), Box<dyn std::error::Error>> {
+        for i in keys {
+            lsm.memtable.insert(format!("k{:02}", i), Some(format!("{}{:02}", version, i)).into());
+        }
+        let flushed = lsm.flush_memtable()?;
+        lsm.segments.extend(flushed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_matches_execution() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(100).segment_size(4).build()?;
+        flush_unmerged(&mut lsm, 0..6, "old")?;
+        flush_unmerged(&mut lsm, 0..6, "new")?;
+        flush_unmerged(&mut lsm, 10..13, "new")?;
+        lsm.delete_unchecked("k20")?;
+        let flushed = lsm.flush_memtable()?;
+        lsm.segments.extend(flushed);
+
+        let plan = lsm.plan_compaction();
+        let bytes_before: u64 = lsm.segment_stats().iter().map(|s| s.total_bytes).sum();
+        assert_eq!(plan.inputs.len(), lsm.segments.len());
+        assert_eq!(plan.bytes_to_read, bytes_before);
+        assert_eq!(plan.records_to_read, 6 + 6 + 3 + 1);
+        assert_eq!(plan.tombstones_kept, 1);
+        assert_eq!(plan.estimated_shadowed_dropped, 6);
+        assert_eq!(plan.output_segments, vec![4, 4, 2]);
+
+        lsm.compact_with_plan(plan.clone())?;
+        let stats = lsm.segment_stats();
+        assert_eq!(stats.iter().map(|s| s.shadowed_dropped).sum::<u64>(), plan.estimated_shadowed_dropped);
+        assert_eq!(stats.iter().map(|s| s.tombstones).sum::<u64>(), plan.tombstones_kept);
+        assert_eq!(lsm.segments.iter().map(|s| s.size()).collect::<Vec<_>>(), plan.output_segments);
+        //the estimate assumes dropped records are average sized
+        let written: u64 = stats.iter().map(|s| s.total_bytes).sum();
+        assert!((written as f64 - plan.estimated_bytes_to_write as f64).abs() < written as f64 * 0.1);
+        for i in 0..6 {
+            assert_eq!(lsm.read(&format!("k{:02}", i))?, Some(format!("new{:02}", i)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlap_estimate_is_upper_bound() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(100).segment_size(100).build()?;
+        //interleaved ranges that share no keys
+        for i in 0..5 {
+            lsm.memtable.insert(format!("k{}a", i), Some("v".to_owned()).into());
+        }
+        let flushed = lsm.flush_memtable()?;
+        lsm.segments.extend(flushed);
+        for i in 0..5 {
+            lsm.memtable.insert(format!("k{}b", i), Some("v".to_owned()).into());
+        }
+        let flushed = lsm.flush_memtable()?;
+        lsm.segments.extend(flushed);
+
+        let plan = lsm.plan_compaction();
+        assert_eq!(plan.estimated_shadowed_dropped, 5);
+        lsm.compact_with_plan(plan)?;
+        assert_eq!(lsm.segment_stats()[0].shadowed_dropped, 0);
+        assert_eq!(lsm.segments[0].size(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(100).segment_size(4).sparse_offset(2).build()?;
+        let report = lsm.compact()?;
+        assert_eq!((report.segments_before, report.segments_after, report.records_after), (0, 0, 0));
+
+        flush_unmerged(&mut lsm, 0..6, "old")?;
+        let report = lsm.compact()?;
+        assert_eq!((report.segments_before, report.segments_after, report.shadowed_dropped), (2, 2, 0));
+        flush_unmerged(&mut lsm, 0..3, "new")?;
+        lsm.memtable.insert("k05".to_owned(), None.into());
+        let flushed = lsm.flush_memtable()?;
+        lsm.segments.extend(flushed);
+        //unflushed writes stay in the memtable and keep winning over the segments
+        lsm.write("k01", "memtable")?;
+        lsm.write("k09", "memtable")?;
+
+        let report = lsm.compact()?;
+        assert_eq!(report.segments_before, 4);
+        assert_eq!(report.records_before, 6 + 3 + 1);
+        assert_eq!(report.shadowed_dropped, 4);
+        assert_eq!(report.expired_dropped, 0);
+        assert_eq!(report.tombstones_kept, 1);
+        assert_eq!(report.records_after, 6);
+        assert_eq!(report.segments_after, 2);
+        assert_eq!(lsm.memtable.len(), 2);
+
+        assert_eq!(lsm.read("k00")?, Some("new00".to_owned()));
+        assert_eq!(lsm.read("k01")?, Some("memtable".to_owned()));
+        assert_eq!(lsm.read("k04")?, Some("old04".to_owned()));
+        assert_eq!(lsm.read("k05")?, None);
+        assert_eq!(lsm.read("k09")?, Some("memtable".to_owned()));
+        assert_eq!(lsm.keys()?.collect::<Result<Vec<_>, _>>()?.len(), 6);
+
+        //already compacted down to one segment
+        let mut lsm = LSMBuilder::new().inmemory_capacity(100).segment_size(100).build()?;
+        flush_unmerged(&mut lsm, 0..3, "v")?;
+        let before = lsm.segment_stats();
+        let report = lsm.compact()?;
+        assert_eq!((report.segments_before, report.segments_after, report.records_after), (1, 1, 3));
+        assert_eq!(lsm.segment_stats(), before);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stale_plan_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(2).segment_size(4).build()?;
+        assert_eq!(lsm.plan_compaction().inputs, vec![]);
+        for i in 0..3 {
+            lsm.write(format!("k{}", i), "v")?;
+        }
+        let plan = lsm.plan_compaction();
+        assert_eq!(plan.inputs.len(), 1);
+
+        for i in 3..6 {
+            lsm.write(format!("k{}", i), "v")?;
+        }
+        assert!(matches!(lsm.compact_with_plan(plan), Err(Error::StaleCompactionPlan)));
+        lsm.compact_with_plan(lsm.plan_compaction())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use crate::{LSMEngine, Result};
+use std::path::PathBuf;
+
+
+/// The settings an engine is currently running with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub segment_size: usize,
+    pub inmemory_capacity: usize,
+    pub sparse_offset: usize,
+    pub compaction_rate_limit: Option<u64>,
+    pub wal_path: Option<PathBuf>,
+}
+
+/// A partial update for `LSMEngine::update_config`. Fields left as `None` are untouched.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigDelta {
+    /// takes effect from the next merge
+    pub segment_size: Option<usize>,
+    /// shrinking below the number of buffered entries flushes the memtable right away
+    pub inmemory_capacity: Option<usize>,
+    /// takes effect from the next merge
+    pub sparse_offset: Option<usize>,
+    /// `Some(None)` removes the limit
+    pub compaction_rate_limit: Option<Option<u64>>,
+    /// fixed at build time; always rejected
+    pub wal_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppliedConfig {
+    pub applied: Vec<&'static str>,
+    pub rejected: Vec<(&'static str, String)>,
+    pub config: Config,
+}
+
+/// The rules shared by `LSMBuilder::build` and `LSMEngine::update_config`.
+pub(crate) fn validate(segment_size: usize, inmemory_capacity: usize, sparse_offset: usize) -> std::result::Result<(), String> {
+    if segment_size == 0 {
+        return Err("segment size must be at least 1".to_owned());
+    }
+    if inmemory_capacity == 0 {
+        return Err("in-memory capacity must be at least 1".to_owned());
+    }
+    if sparse_offset == 0 {
+        return Err("sparse offset must be at least 1".to_owned());
+    }
+    Ok(())
+}
+
+impl LSMEngine {
+    pub fn config(&self) -> Config {
+        Config {
+            segment_size: self.segment_size,
+            inmemory_capacity: self.memtable.capacity(),
+            sparse_offset: self.sparse_offset,
+            compaction_rate_limit: self.compaction_rate_limit(),
+            wal_path: self.wal_path.clone(),
+        }
+    }
+
+    /// Applies whichever settings in `changes` are valid and can change at runtime.
+    /// Each field is checked against the configuration as updated by the fields before it,
+    /// and rejected fields are reported with the reason rather than failing the whole update.
+    pub fn update_config(&mut self, changes: ConfigDelta) -> Result<AppliedConfig> {
+        let mut applied = vec![];
+        let mut rejected = vec![];
+
+        if changes.wal_path.is_some() {
+            rejected.push(("wal_path", "the WAL path cannot be changed after build".to_owned()));
+        }
+
+        if let Some(segment_size) = changes.segment_size {
+            match validate(segment_size, self.memtable.capacity(), self.sparse_offset) {
+                Ok(()) => {
+                    self.segment_size = segment_size;
+                    applied.push("segment_size");
+                }
+                Err(reason) => rejected.push(("segment_size", reason)),
+            }
+        }
+
+        if let Some(capacity) = changes.inmemory_capacity {
+            match validate(self.segment_size, capacity, self.sparse_offset) {
+                Ok(()) => {
+                    self.memtable.set_capacity(capacity);
+                    if self.memtable.at_capacity() {
+                        self.flush_and_merge()?;
+                    }
+                    applied.push("inmemory_capacity");
+                }
+                Err(reason) => rejected.push(("inmemory_capacity", reason)),
+            }
+        }
+
+        if let Some(sparse_offset) = changes.sparse_offset {
+            match validate(self.segment_size, self.memtable.capacity(), sparse_offset) {
+                Ok(()) => {
+                    self.sparse_offset = sparse_offset;
+                    applied.push("sparse_offset");
+                }
+                Err(reason) => rejected.push(("sparse_offset", reason)),
+            }
+        }
+
+        if let Some(limit) = changes.compaction_rate_limit {
+            self.set_compaction_rate_limit(limit);
+            applied.push("compaction_rate_limit");
+        }
+
+        Ok(AppliedConfig { applied, rejected, config: self.config() })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{LSMBuilder, Error};
+    use crate::config::ConfigDelta;
+
+    #[test]
+    fn test_update_config() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().segment_size(10).inmemory_capacity(5).sparse_offset(2).build()?;
+        let result = lsm.update_config(ConfigDelta {
+            segment_size: Some(0),
+            sparse_offset: Some(3),
+            compaction_rate_limit: Some(Some(1000)),
+            wal_path: Some("elsewhere".into()),
+            ..ConfigDelta::default()
+        })?;
+        assert_eq!(result.applied, vec!["sparse_offset", "compaction_rate_limit"]);
+        let rejected: Vec<_> = result.rejected.iter().map(|(field, _)| *field).collect();
+        assert_eq!(rejected, vec!["wal_path", "segment_size"]);
+        assert_eq!(result.config, lsm.config());
+        assert_eq!(lsm.config().segment_size, 10);
+        assert_eq!(lsm.config().sparse_offset, 3);
+        assert_eq!(lsm.compaction_rate_limit(), Some(1000));
+        Ok(())
+    }
+
+    #[test]
+    fn test_shrinking_memtable_flushes() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().segment_size(10).inmemory_capacity(5).build()?;
+        for i in 0..4 {
+            lsm.write(format!("k{}", i), format!("v{}", i))?;
+        }
+        assert_eq!(lsm.segments.len(), 0);
+
+        lsm.update_config(ConfigDelta { inmemory_capacity: Some(2), ..ConfigDelta::default() })?;
+        assert!(!lsm.memtable.at_capacity());
+        assert_eq!(lsm.segments.len(), 1);
+        for i in 0..4 {
+            assert_eq!(lsm.read(&format!("k{}", i))?, Some(format!("v{}", i)));
+        }
+
+        //the memtable now flushes every two new keys
+        for i in 4..8 {
+            lsm.write(format!("k{}", i), format!("v{}", i))?;
+        }
+        assert_eq!(lsm.segments.iter().map(|s| s.size()).sum::<u64>(), 6);
+        assert_eq!(lsm.check_invariants(), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_config() {
+        let result = LSMBuilder::new().segment_size(0).build();
+        assert!(matches!(result, Err(Error::InvalidConfig(_))));
+        let result = LSMBuilder::new().sparse_offset(0).build();
+        assert!(matches!(result, Err(Error::InvalidConfig(_))));
+    }
+}
@@ -0,0 +1,113 @@
+use bloom::BloomFilter;
+use std::convert::TryFrom;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+fn key_hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Collects the keys of a segment while it's being written, so its filter can be
+/// sized for the number of records it actually ends up with.
+#[derive(Default)]
+pub struct FilterBuilder {
+    hashes: Vec<u64>,
+}
+
+impl FilterBuilder {
+    pub fn add(&mut self, key: &str) {
+        self.hashes.push(key_hash(key));
+    }
+
+    pub fn build(self, false_positive_rate: f32) -> KeyFilter {
+        let keys = self.hashes.len();
+        let expected_keys = u32::try_from(keys.max(1)).unwrap_or(u32::MAX);
+        let mut bloom = BloomFilter::with_rate(false_positive_rate, expected_keys);
+        for hash in self.hashes.iter() {
+            bloom.insert(hash);
+        }
+
+        //the filter is sized for `expected_keys` the same way `BloomFilter::with_rate` does it
+        let bits = (expected_keys as f64 * bits_per_key(false_positive_rate)).ceil();
+        let hashes = (bits / expected_keys as f64 * 2f64.ln()).round().max(1.0);
+        KeyFilter {
+            bloom,
+            bits_per_key: bits_per_key(false_positive_rate),
+            expected_fp_rate: expected_fp_rate(bits, hashes, keys as f64),
+        }
+    }
+}
+
+/// Chance that a bloom filter of `bits` bits with `hashes` hash functions holding `keys` keys
+/// claims to hold a key it doesn't.
+fn expected_fp_rate(bits: f64, hashes: f64, keys: f64) -> f64 {
+    (1.0 - (-hashes * keys / bits).exp()).powf(hashes)
+}
+
+/// Bits per key an optimally sized bloom filter needs for the given false positive rate.
+pub fn bits_per_key(false_positive_rate: f32) -> f64 {
+    -(false_positive_rate as f64).ln() / (2f64.ln() * 2f64.ln())
+}
+
+/// False positive rate of an optimally sized bloom filter with the given bits per key.
+pub fn false_positive_rate(bits_per_key: f64) -> f32 {
+    (-bits_per_key * 2f64.ln() * 2f64.ln()).exp() as f32
+}
+
+pub struct KeyFilter {
+    bloom: BloomFilter,
+    bits_per_key: f64,
+    expected_fp_rate: f64,
+}
+
+impl KeyFilter {
+    /// `false` means the key is definitely not in the segment.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bloom.contains(&key_hash(key))
+    }
+
+    pub fn bits_per_key(&self) -> f64 {
+        self.bits_per_key
+    }
+
+    /// False positive rate expected from the filter's size and the number of keys it holds.
+    pub fn expected_fp_rate(&self) -> f64 {
+        self.expected_fp_rate
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter_with(keys: usize, rate: f32) -> KeyFilter {
+        let mut builder = FilterBuilder::default();
+        for i in 0..keys {
+            builder.add(&format!("key{}", i));
+        }
+        builder.build(rate)
+    }
+
+    #[test]
+    fn test_no_false_negatives() {
+        let filter = filter_with(1000, 0.01);
+        for i in 0..1000 {
+            assert!(filter.may_contain(&format!("key{}", i)));
+        }
+    }
+
+    #[test]
+    fn test_false_positive_rate_tracks_target_at_any_size() {
+        for &keys in &[10, 10_000, 1_000_000] {
+            let filter = filter_with(keys, 0.01);
+            assert!((filter.expected_fp_rate() - 0.01).abs() < 0.005, "{} keys: {}", keys, filter.expected_fp_rate());
+
+            let absent = (0..10_000).filter(|i| filter.may_contain(&format!("absent{}", i))).count();
+            assert!(absent <= 300, "{} keys: {} false positives", keys, absent);
+        }
+        assert_eq!(filter_with(0, 0.01).expected_fp_rate(), 0.0);
+    }
+}
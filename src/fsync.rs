@@ -0,0 +1,27 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+#[cfg(test)]
+thread_local! {
+    //lets tests check that every file-lifecycle site goes through here
+    pub static DIR_SYNCS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Fsyncs the directory containing `path`, so that creating, renaming or deleting `path`
+/// survives a crash. Syncing the file alone doesn't persist its directory entry on e.g. ext4.
+///
+/// Directories can't be opened as files on Windows, where this is a no-op.
+pub fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    #[cfg(test)]
+    DIR_SYNCS.with(|count| count.set(count.get() + 1));
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if cfg!(windows) {
+        return Ok(());
+    }
+    File::open(parent)?.sync_all()
+}
@@ -0,0 +1,88 @@
+use crate::{LSMEngine, Result, is_reserved};
+
+
+/// Where the live version of a key was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tier {
+    Memtable,
+    /// position of the segment in `segment_stats()`, oldest first
+    Segment(usize),
+}
+
+/// What `head` knows about a value without handing it out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueMeta {
+    /// length of the value in bytes
+    pub len: u64,
+    pub tier: Tier,
+}
+
+impl LSMEngine {
+    /// Reports whether `key` exists and how big its value is, without returning the value.
+    ///
+    /// Segment records are JSON lines, so the record's line is still read from disk,
+    /// but the value is measured in place rather than copied into its own `String`.
+    pub fn head(&mut self, key: &str) -> Result<Option<ValueMeta>> {
+        if is_reserved(key) {
+            return Ok(None);
+        }
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(value.live().map(|value| ValueMeta { len: value.len() as u64, tier: Tier::Memtable }));
+        }
+
+        let (key_offset, segment_index) = match self.closest_index_entry(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        for index in segment_index..self.segments.len() {
+            let segment = &mut self.segments[index];
+            if !segment.may_contain(key) {
+                continue;
+            }
+            let offset = if index == segment_index { key_offset } else { 0 };
+            if let Some(value) = segment.value_len_from(key, offset)? {
+                if !value.is_live() {
+                    return Ok(None);
+                }
+                return Ok(Some(ValueMeta { len: value.len, tier: Tier::Segment(index) }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{LSMBuilder, ValueMeta, Tier};
+
+    #[test]
+    fn test_head() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(3).segment_size(3).sparse_offset(2).build()?;
+        let big = "x".repeat(1 << 20);
+        lsm.write("big", big.as_str())?;
+        lsm.write("a", "1")?;
+        lsm.write("b", "22")?;
+        lsm.write("c", "333")?;
+        lsm.write("d", "4444")?;
+        lsm.write("é", "ü")?;
+        lsm.delete("b")?;
+        lsm.write("e", "55555")?;
+
+        assert_eq!(lsm.head("e")?, Some(ValueMeta { len: 5, tier: Tier::Memtable }));
+        assert_eq!(lsm.head("é")?, Some(ValueMeta { len: 2, tier: Tier::Segment(1) }));
+        assert_eq!(lsm.head("big")?, Some(ValueMeta { len: 1 << 20, tier: Tier::Segment(0) }));
+        assert_eq!(lsm.head("a")?, Some(ValueMeta { len: 1, tier: Tier::Segment(0) }));
+        assert_eq!(lsm.head("d")?, Some(ValueMeta { len: 4, tier: Tier::Segment(1) }));
+        assert_eq!(lsm.head("b")?, None);
+        assert_eq!(lsm.head("missing")?, None);
+        assert_eq!(lsm.head("0")?, None);
+
+        for key in &["a", "big", "c", "d", "e", "é"] {
+            let len = lsm.read(key)?.map(|v| v.len() as u64);
+            assert_eq!(lsm.head(key)?.map(|meta| meta.len), len);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use std::ops::RangeInclusive;
+use crate::wal::WAL_VERSION;
+
+
+/// What this build of the engine is and which on-disk formats it can read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildInfo {
+    pub crate_version: &'static str,
+    /// cargo features the crate was compiled with
+    pub features: Vec<&'static str>,
+    /// WAL format versions this build replays. New WALs are written with the highest one
+    pub wal_versions: RangeInclusive<u32>,
+    /// optional WAL format features this build understands. A WAL whose header lists any other
+    /// feature is refused with `Error::UnsupportedWalFeature`
+    pub wal_features: Vec<&'static str>,
+}
+
+/// Reports the crate version and supported formats, e.g. for attaching to bug reports.
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        crate_version: env!("CARGO_PKG_VERSION"),
+        features: vec![],
+        wal_versions: 0..=WAL_VERSION,
+        wal_features: vec![],
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::build_info;
+    use crate::wal::WAL_VERSION;
+
+    #[test]
+    fn test_build_info() {
+        let info = build_info();
+        assert_eq!(info.crate_version, env!("CARGO_PKG_VERSION"));
+        assert!(info.wal_versions.contains(&0));
+        assert_eq!(*info.wal_versions.end(), WAL_VERSION);
+        assert!(info.wal_features.is_empty());
+    }
+}
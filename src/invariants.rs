@@ -0,0 +1,131 @@
+use crate::LSMEngine;
+
+
+/// Stores holding at most this many records, on disk and in the memtable, get `len` checked against a full count.
+const LEN_CHECK_MAX_RECORDS: u64 = 10_000;
+
+/// An internal invariant that `LSMEngine::check_invariants` found broken.
+/// Segments are numbered by their position in `segment_stats()`, oldest first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvariantViolation {
+    /// a sparse index entry points past the last segment, or at a record with another key
+    IndexEntryUnresolved { key: String, segment: usize, offset: u64 },
+    /// `current` comes at or before `previous`, either within `segment` or right after the segment before it
+    KeysOutOfOrder { segment: usize, previous: String, current: String },
+    MemtableOverCapacity { entries: usize, capacity: usize },
+    /// the segment's file is not as long as what was written to it
+    SegmentSizeMismatch { segment: usize, written: u64, on_disk: u64 },
+    /// `len` disagrees with a count of the keys holding a value
+    LenMismatch { len: u64, counted: u64 },
+    /// reading a segment failed, so the checks that needed it were cut short
+    ReadFailed { error: String },
+}
+
+impl LSMEngine {
+    /// Checks the engine's internal bookkeeping and returns every violation found, e.g. to assert
+    /// the engine's health at the end of a test. It only reads, so it's safe to call whenever no
+    /// scan is open. It checks that
+    /// * every sparse index entry resolves to a record with its key
+    /// * segments are sorted, spot checked at the indexed keys, and follow each other in key order
+    /// * the memtable is within its capacity
+    /// * each segment file is as long as what was written to it
+    /// * `len` matches a full count of the keys, on stores of at most 10,000 records
+    pub fn check_invariants(&mut self) -> std::result::Result<(), Vec<InvariantViolation>> {
+        let mut violations = vec![];
+        if self.memtable.len() > self.memtable.capacity() {
+            violations.push(InvariantViolation::MemtableOverCapacity { entries: self.memtable.len(), capacity: self.memtable.capacity() });
+        }
+
+        for (index, segment) in self.segments.iter().enumerate() {
+            match segment.file_len() {
+                Ok(on_disk) if on_disk != segment.stats().total_bytes => {
+                    violations.push(InvariantViolation::SegmentSizeMismatch { segment: index, written: segment.stats().total_bytes, on_disk });
+                }
+                Ok(_) => {}
+                Err(e) => violations.push(InvariantViolation::ReadFailed { error: e.to_string() }),
+            }
+        }
+        for (index, pair) in self.segments.windows(2).enumerate() {
+            if let (Some((_, last)), Some((first, _))) = (pair[0].key_range(), pair[1].key_range()) {
+                if last >= first {
+                    violations.push(InvariantViolation::KeysOutOfOrder { segment: index + 1, previous: last.to_owned(), current: first.to_owned() });
+                }
+            }
+        }
+
+        //the index is in key order, so the positions it records must only move forward
+        let mut previous: Option<(&String, u64, usize)> = None;
+        for (key, &(offset, segment)) in self.sparse_memory_index.iter() {
+            if let Some((previous_key, previous_offset, previous_segment)) = previous {
+                if (segment, offset) <= (previous_segment, previous_offset) {
+                    violations.push(InvariantViolation::KeysOutOfOrder { segment, previous: previous_key.clone(), current: key.clone() });
+                }
+            }
+            previous = Some((key, offset, segment));
+
+            let unresolved = InvariantViolation::IndexEntryUnresolved { key: key.clone(), segment, offset };
+            match self.segments.get_mut(segment).map(|s| s.record_at(offset)) {
+                None | Some(Ok(None)) => violations.push(unresolved),
+                Some(Ok(Some(kv))) if kv.key != *key => violations.push(unresolved),
+                Some(Ok(Some(_))) => {}
+                Some(Err(e)) => violations.push(InvariantViolation::ReadFailed { error: e.to_string() }),
+            }
+        }
+
+        let records = self.segments.iter().map(|s| s.size()).sum::<u64>() + self.memtable.len() as u64;
+        if records <= LEN_CHECK_MAX_RECORDS {
+            match self.count_keys_with_values() {
+                Ok(counted) if counted != self.live_keys => {
+                    violations.push(InvariantViolation::LenMismatch { len: self.live_keys, counted });
+                }
+                Ok(_) => {}
+                Err(e) => violations.push(InvariantViolation::ReadFailed { error: e.to_string() }),
+            }
+        }
+
+        if violations.is_empty() {
+            return Ok(());
+        }
+        Err(violations)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{LSMBuilder, InvariantViolation};
+    use crate::sst::Segment;
+    use crate::kv::KVPair;
+
+    #[test]
+    fn test_check_invariants() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(3).segment_size(2).sparse_offset(2).build()?;
+        assert_eq!(lsm.check_invariants(), Ok(()));
+        for i in 0..10 {
+            lsm.write(format!("k{}", i), "v")?;
+        }
+        lsm.delete("k4")?;
+        assert_eq!(lsm.check_invariants(), Ok(()));
+
+        lsm.sparse_memory_index.insert("k55".to_owned(), (0, 1));
+        lsm.sparse_memory_index.insert("k99".to_owned(), (0, 40));
+        lsm.live_keys += 1;
+        lsm.memtable.set_capacity(0);
+        let mut stray = Segment::temp();
+        stray.write(KVPair { key: "a".to_owned(), value: None, expires_at: None })?;
+        lsm.segments.push(stray);
+
+        let violations = lsm.check_invariants().unwrap_err();
+        let last_key = lsm.segments[lsm.segments.len() - 2].key_range().unwrap().1.to_owned();
+        assert_eq!(violations, vec![
+            InvariantViolation::MemtableOverCapacity { entries: 2, capacity: 0 },
+            InvariantViolation::KeysOutOfOrder { segment: 5, previous: last_key, current: "a".to_owned() },
+            //k55 sorts between the indexed k4 and k6, but its entry points back at k2
+            InvariantViolation::KeysOutOfOrder { segment: 1, previous: "k4".to_owned(), current: "k55".to_owned() },
+            InvariantViolation::IndexEntryUnresolved { key: "k55".to_owned(), segment: 1, offset: 0 },
+            InvariantViolation::IndexEntryUnresolved { key: "k99".to_owned(), segment: 40, offset: 0 },
+            InvariantViolation::LenMismatch { len: 10, counted: 9 },
+        ]);
+        Ok(())
+    }
+}
@@ -1,18 +1,99 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::{self, Visitor};
+use std::borrow::Cow;
 #[macro_use]
 use thiserror::Error;
 use std::fs::File;
 use std::convert::TryFrom;
-use std::io::{SeekFrom, Seek, BufReader, BufRead, Write};
+use std::io::{SeekFrom, Seek, Write, BufRead, Read};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 
 pub(crate) type Result<T> = std::result::Result<T, KvError>;
 
+/// Longest record line, newline excluded, read back from a WAL or segment unless configured otherwise.
+pub const DEFAULT_MAX_RECORD_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Serializes `record` as a single newline-terminated line. serde_json escapes control characters,
+/// so a newline inside a key or value can't split the record, but this is checked rather than
+/// assumed, since readers split records on newlines.
+pub(crate) fn framed<T: Serialize>(record: &T) -> Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(record)?;
+    if line.contains(&b'\n') {
+        return Err(KvError::UnframedRecord);
+    }
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Reads one newline-terminated record starting at `offset` into `line`, refusing to buffer more
+/// than `limit` bytes before the newline, so a damaged or crafted file can't make a reader allocate
+/// without bound. Returns the bytes read, 0 only at the end of the input: every call either
+/// consumes input or reports the end, so loops over it always terminate.
+/// The bytes are left undecoded, so a record cut off inside a multibyte character still reads as
+/// a line without its newline rather than failing.
+pub(crate) fn read_record_line<R: BufRead>(reader: &mut R, line: &mut Vec<u8>, limit: u64, offset: u64) -> Result<usize> {
+    line.clear();
+    let read = reader.by_ref().take(limit.saturating_add(1)).read_until(b'\n', line)?;
+    if read as u64 > limit && line.last() != Some(&b'\n') {
+        return Err(KvError::RecordTooLarge { offset, limit });
+    }
+    Ok(read)
+}
+
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
 pub struct KVPair {
     pub key: String,
-    pub value: String,
+    /// `None` marks a delete, stored as `null` so that no string value is ever mistaken for one
+    pub value: Option<String>,
+    /// unix time in milliseconds from which the value reads as absent, left out of records without a TTL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+impl KVPair {
+    /// Splits the record into its key and what the memtable holds for it.
+    pub fn into_entry(self) -> (String, StoredValue) {
+        (self.key, StoredValue { value: self.value, expires_at: self.expires_at })
+    }
+
+    pub fn from_entry(key: String, stored: StoredValue) -> Self {
+        KVPair { key, value: stored.value, expires_at: stored.expires_at }
+    }
+}
+
+/// A key's value as the engine holds it, with its expiry.
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct StoredValue {
+    /// `None` marks a delete
+    pub value: Option<String>,
+    pub expires_at: Option<u64>,
+}
+
+impl StoredValue {
+    /// What a read should see: nothing for deletes and expired values.
+    pub fn live(&self) -> Option<&str> {
+        if crate::ttl::is_expired(self.expires_at, crate::ttl::now_millis()) {
+            return None;
+        }
+        self.value.as_deref()
+    }
+}
+
+//the expiry is bookkeeping rather than data, so only the value counts
+impl crate::memtable::ByteLen for StoredValue {
+    fn byte_len(&self) -> usize {
+        self.value.byte_len()
+    }
+}
+
+impl From<Option<String>> for StoredValue {
+    fn from(value: Option<String>) -> Self {
+        StoredValue { value, expires_at: None }
+    }
 }
 
 
@@ -26,6 +107,61 @@ impl TryFrom<String> for KVPair {
 }
 
 
+/// A record's key and the length of its value, parsed without copying the value out of the line.
+#[derive(Deserialize, Debug)]
+pub struct RecordHead<'a> {
+    #[serde(borrow)]
+    pub key: Cow<'a, str>,
+    pub value: ValueLen,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+/// Length in bytes of a value and whether the record is a delete, along with the record's expiry.
+/// Parsing a value on its own leaves `expires_at` unset.
+#[derive(Debug, PartialEq)]
+pub struct ValueLen {
+    pub len: u64,
+    pub is_tombstone: bool,
+    pub expires_at: Option<u64>,
+}
+
+impl ValueLen {
+    /// Whether a read would return the value, i.e. it's neither deleted nor expired.
+    pub fn is_live(&self) -> bool {
+        !self.is_tombstone && !crate::ttl::is_expired(self.expires_at, crate::ttl::now_millis())
+    }
+}
+
+impl<'de> Deserialize<'de> for ValueLen {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct ValueLenVisitor;
+
+        impl<'de> Visitor<'de> for ValueLenVisitor {
+            type Value = ValueLen;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string value or null")
+            }
+
+            fn visit_none<E: de::Error>(self) -> std::result::Result<ValueLen, E> {
+                Ok(ValueLen { len: 0, is_tombstone: true, expires_at: None })
+            }
+
+            fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> std::result::Result<ValueLen, D::Error> {
+                deserializer.deserialize_str(self)
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> std::result::Result<ValueLen, E> {
+                Ok(ValueLen { len: value.len() as u64, is_tombstone: false, expires_at: None })
+            }
+        }
+
+        deserializer.deserialize_option(ValueLenVisitor)
+    }
+}
+
+
 #[derive(Error, Debug)]
 pub enum KvError {
     #[error(transparent)]
@@ -34,6 +170,40 @@ pub enum KvError {
     #[error(transparent)]
     FileIOError(#[from] std::io::Error),
 
+    #[error("record does not serialize to a single line")]
+    UnframedRecord,
+
+    #[error("record at byte {} is longer than the {} byte limit", offset, limit)]
+    RecordTooLarge { offset: u64, limit: u64 },
+
+    #[error("record at byte {} does not match its checksum", offset)]
+    ChecksumMismatch { offset: u64 },
+
+}
+
+static NEXT_TEMP_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Identifies the file behind a segment or WAL in errors and stats:
+/// its path if it has one, or a process-unique `temp-N` label otherwise.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FileId {
+    Path(PathBuf),
+    Temp(usize),
+}
+
+impl FileId {
+    pub fn temp() -> Self {
+        FileId::Temp(NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for FileId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileId::Path(path) => write!(f, "{}", path.display()),
+            FileId::Temp(id) => write!(f, "temp-{}", id),
+        }
+    }
 }
 
 pub trait KVFileIterator {
@@ -55,29 +225,11 @@ pub trait KVFileIterator {
     }
 }
 
-pub trait KVFileReader: KVFileIterator {
-    fn read(&mut self) -> Box<dyn Iterator<Item=Result<KVPair>> + '_> {
-        let reader = BufReader::new(self.file_as_mut());
-
-        return Box::new(reader.lines().map(|string| {
-            KVPair::try_from(string?)
-        }));
-    }
-
-    fn read_from_start(&mut self) -> Result<Box<dyn Iterator<Item=Result<KVPair>> + '_>> {
-        self.seek(0)?;
-        let reader = BufReader::new(self.file_as_mut());
-        return Ok(Box::new(reader.lines().map(|string| {
-            KVPair::try_from(string?)
-        })));
-    }
-}
-
 pub trait KVFileWriter: KVFileIterator {
-    fn persist(&mut self, kv: KVPair) -> Result<u64> {
+    fn persist(&mut self, kv: &KVPair) -> Result<u64> {
         let current_offset = self.tell()?;
-        serde_json::to_writer(self.file_as_mut(), &kv)?;
-        self.file_as_mut().write(b"\n")?;
+        let line = framed(kv)?;
+        self.file_as_mut().write_all(&line)?;
         return Ok(current_offset);
     }
 }
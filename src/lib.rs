@@ -14,14 +14,14 @@
  //!         inmemory_capacity(100). //store only 100 entries in memory
  //!         sparse_offset(20). //store one out of every 20 entries written into segments in memory
  //!         wal_path("my_write_ahead_log.txt"). //path
- //!         build();
+ //!         build()?;
  //!
- //!    let mut default_lsm = LSMBuilder::new().build(); //an lsm engine with default parameters
+ //!    let mut default_lsm = LSMBuilder::new().build()?; //an lsm engine with default parameters
  //!
  //!    let dataset = vec![("k1", "v1"), ("k2", "v2"), ("k1", "v_1_1")];
  //!
  //!    for (k, v) in dataset.iter() {
- //!         lsm.write(String::from(*k), String::from(*v));
+ //!         lsm.write(*k, *v)?; // anything that converts into a String, e.g. &str literals
  //!     }
  //!     assert_eq!(lsm.read("k1")?, Some("v_1_1".to_owned()));
  //!
@@ -62,7 +62,7 @@
 //! * It then linearly scans forward from that offset, looking for the desired key-value entry.
 //!
 //! ### Delete
-//! This is just a special case of write, with value being a special tombstone string.
+//! This is just a special case of write, with a record that holds no value.
 //! For more details with visual illustrations, check out this [blog post](https://navyazaveri.github.io/algorithms/2020/01/12/write-a-kv-store-from-scratch.html)
 //!
 
@@ -70,20 +70,13 @@ use crate::memtable::{Memtable};
 use crate::sst::{Segment};
 use std::collections::BTreeMap;
 use std::ops::Bound::{Included, Unbounded};
-use rand::Rng;
 use thiserror::Error;
-use rand::distributions::Alphanumeric;
-use crate::kv::{KVPair, KVFileWriter, KVFileReader};
+use crate::kv::{KVPair, StoredValue};
+pub use crate::kv::{FileId, KvError};
 use crate::wal::Wal;
-use std::fs::{File, OpenOptions};
-use std::path::Path;
-use rand::{SeedableRng};
-
-extern crate bloom;
-
-
-use rand::rngs::StdRng;
-use bloom::BloomFilter;
+use crate::throttle::RateLimiter;
+use std::fs::File;
+use std::path::{Path, PathBuf};
 
 
 #[macro_use]
@@ -94,14 +87,56 @@ mod memtable;
 mod sst;
 mod wal;
 mod kv;
-lazy_static! {
-
-static ref TOMBSTONE_VALUE: String = {
-    let rng:StdRng = SeedableRng::seed_from_u64(20);
-    rng.sample_iter(&Alphanumeric).take(20).collect::<String>()
-    };
+mod throttle;
+mod warmup;
+mod config;
+mod fsync;
+mod quiesce;
+mod filter;
+mod compaction;
+mod recovery;
+mod head;
+mod trace;
+mod scan;
+mod batch;
+mod info;
+mod ttl;
+mod checkpoint;
+mod audit;
+mod stats;
+mod invariants;
+
+pub use crate::warmup::{WarmupOptions, WarmupReport};
+pub use crate::config::{Config, ConfigDelta, AppliedConfig};
+pub use crate::sst::{SegmentStats, SstError};
+pub use crate::quiesce::QuiesceGuard;
+pub use crate::compaction::{CompactionPlan, CompactionReport};
+pub use crate::recovery::RecoveryReport;
+pub use crate::wal::StopReason;
+pub use crate::head::{ValueMeta, Tier};
+pub use crate::trace::{ReplayReport, replay_trace};
+pub use crate::scan::Scan;
+pub use crate::batch::WriteBatch;
+pub use crate::info::{BuildInfo, build_info};
+pub use crate::audit::{ConsistencyReport, Divergence};
+pub use crate::stats::Stats;
+pub use crate::invariants::InvariantViolation;
+
+/// Keys starting with this character are reserved for records the engine keeps for itself.
+/// User writes to them fail with `Error::ReservedKey` and reads never return them.
+/// It's the largest `char`, so internal records sort after every user key.
+pub const RESERVED_KEY_PREFIX: char = '\u{10FFFF}';
+
+fn is_reserved(key: &str) -> bool {
+    key.starts_with(RESERVED_KEY_PREFIX)
 }
 
+fn check_user_key(key: &str) -> Result<()> {
+    if is_reserved(key) {
+        return Err(Error::ReservedKey { key: key.to_owned() });
+    }
+    Ok(())
+}
 
 type KeyOffset = u64;
 type SegmentIndex = usize;
@@ -112,19 +147,60 @@ pub enum Error {
     SstError(#[from] sst::SstError),
     #[error(transparent)]
     KvError(#[from] kv::KvError),
+
+    #[error("WAL path {} is a directory", path.display())]
+    InvalidWalPath { path: PathBuf },
+
+    #[error("could not open WAL file {}: {}", path.display(), source)]
+    WalUnavailable { path: PathBuf, source: std::io::Error },
+
+    #[error("could not replay WAL {}: {}", wal, source)]
+    WalCorrupted { wal: FileId, source: kv::KvError },
+
+    #[error("WAL {} has format version {}, this build supports up to {}", wal, found, supported)]
+    IncompatibleWal { wal: FileId, found: u32, supported: u32 },
+
+    #[error("WAL {} uses format feature {:?}, which this build does not support", wal, feature)]
+    UnsupportedWalFeature { wal: FileId, feature: String },
+
+    #[error("key {:?} starts with the reserved prefix U+10FFFF", key)]
+    ReservedKey { key: String },
+
+    #[error("invalid configuration: {}", .0)]
+    InvalidConfig(String),
+
+    #[error("compaction plan is stale: the segments changed since it was made")]
+    StaleCompactionPlan,
+
+    #[error("checkpoint I/O failed at {}: {}", path.display(), source)]
+    CheckpointIo { path: PathBuf, source: std::io::Error },
+
+    #[error("no WAL is configured")]
+    WalNotConfigured,
+
+    #[error("checkpoint {} has format version {}, this build supports up to {}", path.display(), found, supported)]
+    IncompatibleCheckpoint { path: PathBuf, found: u32, supported: u32 },
 }
 
 
 pub type Result<T> = std::result::Result<T, self::Error>;
 
 pub struct LSMEngine {
-    memtable: Memtable<String, String>,
+    memtable: Memtable<String, StoredValue>,
     segments: Vec<Segment>,
     segment_size: usize,
     sparse_memory_index: BTreeMap<String, (KeyOffset, SegmentIndex)>,
     sparse_offset: usize,
     wal: Option<Wal>,
-    bloom_filter: BloomFilter,
+    wal_path: Option<PathBuf>,
+    bloom_false_positive_rate: f32,
+    compaction_limiter: RateLimiter,
+    replay_memory_budget: Option<usize>,
+    recovery_report: Option<RecoveryReport>,
+    tracer: Option<trace::Tracer>,
+    live_keys: u64,
+    op_counts: stats::OpCounts,
+    max_record_bytes: u64,
 }
 
 
@@ -133,7 +209,15 @@ pub struct LSMBuilder {
     segment_size: usize,
     sparse_offset: usize,
     inmemory_capacity: usize,
-    wal: Option<Wal>,
+    wal_path: Option<PathBuf>,
+    recover_wal: bool,
+    compaction_rate_limit: Option<u64>,
+    compaction_burst: Option<u64>,
+    durable_renames: Option<bool>,
+    bloom_false_positive_rate: f32,
+    replay_memory_budget: Option<usize>,
+    trace_path: Option<PathBuf>,
+    max_record_bytes: u64,
 }
 
 impl LSMBuilder {
@@ -143,7 +227,15 @@ impl LSMBuilder {
             segment_size: 1500,
             sparse_offset: 35,
             inmemory_capacity: 500,
-            wal: None,
+            wal_path: None,
+            recover_wal: true,
+            compaction_rate_limit: None,
+            compaction_burst: None,
+            durable_renames: None,
+            bloom_false_positive_rate: 0.01,
+            replay_memory_budget: None,
+            trace_path: None,
+            max_record_bytes: kv::DEFAULT_MAX_RECORD_BYTES,
         };
     }
 
@@ -161,14 +253,40 @@ impl LSMBuilder {
         self.sparse_offset = sparse_offset;
         return self;
     }
+
+    /// The WAL file is created on `build()` if it doesn't exist yet. If it already holds records,
+    /// they're replayed into the new engine unless `recover_wal(false)` is set.
     pub fn wal_path<P: AsRef<Path>>(mut self, path: P) -> Self {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path)
-            .unwrap();
-        self.wal = Some(Wal::new(file));
+        self.wal_path = Some(path.as_ref().to_path_buf());
+        return self;
+    }
+
+    /// Fsync the containing directory whenever the engine creates, renames or deletes one of its files,
+    /// so the change itself survives a crash and not just the file contents. Defaults to `persist_data`.
+    /// Has no effect on Windows.
+    pub fn durable_renames(mut self, durable: bool) -> Self {
+        self.durable_renames = Some(durable);
+        return self;
+    }
+
+    /// Target false positive rate of the bloom filter each segment gets, sized from the number
+    /// of records the segment actually holds. Defaults to 1%.
+    pub fn bloom_false_positive_rate(mut self, rate: f32) -> Self {
+        self.bloom_false_positive_rate = rate;
+        return self;
+    }
+
+    /// Sizes each segment's bloom filter by memory instead of by false positive rate.
+    /// 10 bits per key gives roughly a 1% false positive rate. Overrides `bloom_false_positive_rate`.
+    pub fn bloom_bits_per_key(mut self, bits: f64) -> Self {
+        self.bloom_false_positive_rate = filter::false_positive_rate(bits);
+        return self;
+    }
+
+    /// Whether `build()` should replay an existing WAL found at `wal_path`. Defaults to true.
+    /// When turned off, new records are appended after the old ones without replaying them.
+    pub fn recover_wal(mut self, recover: bool) -> Self {
+        self.recover_wal = recover;
         return self;
     }
 
@@ -176,17 +294,74 @@ impl LSMBuilder {
         self.inmemory_capacity = inmemory_capacity;
         return self;
     }
-    pub fn build(self) -> LSMEngine {
-        return LSMEngine::new(self.inmemory_capacity, self.segment_size, self.sparse_offset, self.wal);
+
+    /// Caps how many key/value bytes per second compaction may read and write combined.
+    /// Merges sleep whenever they get ahead of this budget. Unlimited by default.
+    pub fn compaction_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.compaction_rate_limit = Some(bytes_per_sec);
+        return self;
+    }
+
+    /// How many bytes a merge may move at full speed before pacing kicks in.
+    /// Defaults to one second's worth of `compaction_rate_limit`.
+    pub fn compaction_burst(mut self, bytes: u64) -> Self {
+        self.compaction_burst = Some(bytes);
+        return self;
+    }
+
+    /// Caps the bytes of keys and values buffered while replaying the WAL on startup.
+    /// Replay cuts a segment whenever the next record would go over it, on top of the usual
+    /// `inmemory_capacity` limit. Unbounded by default.
+    pub fn replay_memory_budget(mut self, bytes: usize) -> Self {
+        self.replay_memory_budget = Some(bytes);
+        return self;
+    }
+
+    /// Longest record, in bytes, that will be read back from a WAL or a checkpoint's segment files.
+    /// A longer one fails the read with `KvError::RecordTooLarge` rather than being buffered, which
+    /// guards against damaged or crafted files. Defaults to 64 MiB.
+    pub fn max_record_bytes(mut self, bytes: u64) -> Self {
+        self.max_record_bytes = bytes;
+        return self;
+    }
+
+    /// Records every write, read and delete to a trace file at `path`, for replaying later with
+    /// `replay_trace`. Only the length of each value is recorded, never its contents.
+    pub fn trace_to<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.trace_path = Some(path.as_ref().to_path_buf());
+        return self;
+    }
+
+    pub fn build(self) -> Result<LSMEngine> {
+        config::validate(self.segment_size, self.inmemory_capacity, self.sparse_offset).map_err(Error::InvalidConfig)?;
+        if !(self.bloom_false_positive_rate > 0.0 && self.bloom_false_positive_rate < 1.0) {
+            return Err(Error::InvalidConfig(format!("bloom false positive rate {} must be between 0 and 1", self.bloom_false_positive_rate)));
+        }
+        if self.max_record_bytes == 0 {
+            return Err(Error::InvalidConfig("max record bytes must be positive".to_owned()));
+        }
+        let mut lsm = LSMEngine::new(self.inmemory_capacity, self.segment_size, self.sparse_offset, None);
+        lsm.compaction_limiter = RateLimiter::new(self.compaction_rate_limit, self.compaction_burst);
+        lsm.bloom_false_positive_rate = self.bloom_false_positive_rate;
+        lsm.replay_memory_budget = self.replay_memory_budget;
+        lsm.max_record_bytes = self.max_record_bytes;
+        if let Some(path) = self.wal_path {
+            let mut wal = Wal::open(&path, self.durable_renames.unwrap_or(self.persist_data))?;
+            if self.recover_wal {
+                lsm.replay(&mut wal)?;
+            }
+            lsm.wal = Some(wal);
+            lsm.wal_path = Some(path);
+        }
+        if let Some(path) = self.trace_path {
+            lsm.tracer = Some(trace::Tracer::create(path)?);
+        }
+        Ok(lsm)
     }
 }
 
 impl LSMEngine {
     fn new(inmemory_capacity: usize, segment_size: usize, sparse_offset: usize, wal: Option<Wal>) -> Self {
-        if segment_size < inmemory_capacity {
-            panic!("segment size {} cannot be less than in-memory capacity {}", segment_size, inmemory_capacity)
-        }
-
         LSMEngine {
             memtable: Memtable::new(inmemory_capacity),
             segments: Vec::new(),
@@ -194,124 +369,433 @@ impl LSMEngine {
             segment_size,
             sparse_offset,
             wal,
-
-            // we don't care about high false positivity rate (0.9) since we're only using the bloom filter
-            // to detect keys _not_ inserted into the db (ie, false negatives)
-            bloom_filter: BloomFilter::with_rate(0.9, 10000),
-
+            wal_path: None,
+            bloom_false_positive_rate: 0.01,
+            compaction_limiter: RateLimiter::new(None, None),
+            replay_memory_budget: None,
+            recovery_report: None,
+            tracer: None,
+            live_keys: 0,
+            op_counts: stats::OpCounts::default(),
+            max_record_bytes: kv::DEFAULT_MAX_RECORD_BYTES,
         }
     }
 
 
     pub fn recover_from(&mut self, wal_file: File) -> Result<()> {
-        self.clear();
-        let mut wal_file = Wal::new(wal_file);
+        self.clear_in_memory();
+        let mut wal_file = Wal::from_file(wal_file, FileId::temp())?;
+        self.wal = None;
+        self.wal_path = None;
+        self.replay(&mut wal_file)?;
+        self.wal = Some(wal_file);
+        Ok(())
+    }
 
-        for maybe_kv in wal_file.read_from_start()? {
-            let kv = maybe_kv?;
-            self.write(kv.key, kv.value)?;
+    /// Wipes the store: the memtable, every segment and the sparse index are dropped, and the WAL
+    /// is truncated so that recovering from it afterwards yields an empty engine.
+    pub fn clear(&mut self) -> Result<()> {
+        if let Some(wal) = self.wal.as_mut() {
+            wal.clear()?;
         }
-        self.wal = Some(wal_file);
+        self.clear_in_memory();
         Ok(())
     }
 
-    pub fn clear(&mut self) {
+    fn clear_in_memory(&mut self) {
+        self.memtable.clear();
         self.segments.clear();
         self.sparse_memory_index.clear();
-        self.bloom_filter.clear();
+        self.live_keys = 0;
     }
 
 
-    fn flush_memtable(&mut self) -> Result<Segment> {
+    /// Writes the memtable out in key order, rolling over to a new segment every `segment_size` entries
+    /// the same way `sst::merge` does.
+    fn flush_memtable(&mut self) -> Result<Vec<Segment>> {
+        let mut flushed = vec![];
         let mut new_segment = Segment::temp();
-        for (key, value) in self.memtable.drain() {
-            new_segment.write(KVPair { key, value })?;
+        for (key, stored) in self.memtable.drain() {
+            if new_segment.size() == self.segment_size as u64 {
+                new_segment.seal(self.bloom_false_positive_rate);
+                flushed.push(new_segment);
+                new_segment = Segment::temp();
+            }
+            new_segment.write(KVPair::from_entry(key, stored))?;
         }
-        return Ok(new_segment);
+        new_segment.seal(self.bloom_false_positive_rate);
+        flushed.push(new_segment);
+        Ok(flushed)
     }
 
 
+    /// Merges every segment into new ones and reindexes them. The new segments and index are built
+    /// on the side, so a failed merge leaves the old ones in place.
     fn merge_segments(&mut self) -> Result<()> {
-        self.sparse_memory_index.clear();
-        let mut count = 0;
-        self.segments = sst::merge(std::mem::take(&mut self.segments), self.segment_size,
-                                   |segment_index, key_offset, key| {
-                                       if count % self.sparse_offset == 0 {
-                                           self.sparse_memory_index.insert(key, (key_offset, segment_index));
-                                       }
-                                       count += 1;
-                                   })?;
+        let mut index = BTreeMap::new();
+        let mut count: u64 = 0;
+        let sparse_offset = self.sparse_offset as u64;
+        let mut expired = vec![];
+        let merged = sst::merge(&mut self.segments, self.segment_size,
+                                self.bloom_false_positive_rate,
+                                &mut self.compaction_limiter,
+                                |segment_index, key_offset, key| {
+                                    if count.is_multiple_of(sparse_offset) {
+                                        index.insert(key, (key_offset, segment_index));
+                                    }
+                                    count += 1;
+                                },
+                                |key| expired.push(key))?;
+        self.segments = merged;
+        self.sparse_memory_index = index;
+        //a newer version in the memtable still counts, whatever the merge dropped. Replay merges
+        //before it has counted anything, and recounts afterwards
+        let memtable = &self.memtable;
+        self.live_keys = self.live_keys.saturating_sub(expired.iter().filter(|key| !is_reserved(key) && !memtable.contains(key.as_str())).count() as u64);
+        Ok(())
+    }
+
+    /// Rebuilds the sparse index so that every `sparse_offset`th key on disk is indexed, by rescanning
+    /// the segments' keys. Segment files are left untouched, and later merges keep the new density.
+    /// The new index is built on the side and only replaces the old one once it's complete.
+    pub fn rebuild_index_with(&mut self, sparse_offset: usize) -> Result<()> {
+        config::validate(self.segment_size, self.memtable.capacity(), sparse_offset).map_err(Error::InvalidConfig)?;
+        let mut index = BTreeMap::new();
+        let mut count: u64 = 0;
+        for (segment_index, segment) in self.segments.iter_mut().enumerate() {
+            for entry in segment.keys_with_offsets()? {
+                let (key_offset, key) = entry?;
+                if count.is_multiple_of(sparse_offset as u64) {
+                    index.insert(key, (key_offset, segment_index));
+                }
+                count += 1;
+            }
+        }
+        self.sparse_memory_index = index;
+        self.sparse_offset = sparse_offset;
+        Ok(())
+    }
+
+    /// Changes the compaction rate limit at runtime, e.g. to open the throttle during off-peak hours.
+    /// `None` removes the limit. Takes effect from the next merge.
+    pub fn set_compaction_rate_limit(&mut self, bytes_per_sec: Option<u64>) {
+        self.compaction_limiter.set_rate(bytes_per_sec);
+    }
+
+    pub fn compaction_rate_limit(&self) -> Option<u64> {
+        self.compaction_limiter.rate()
+    }
+
+    /// Per-segment bookkeeping, oldest segment first.
+    pub fn segment_stats(&self) -> Vec<SegmentStats> {
+        self.segments.iter().map(|segment| segment.stats().clone()).collect()
+    }
+
+    /// Key/value bytes per second moved by the most recent compaction.
+    pub fn compaction_throughput(&self) -> f64 {
+        self.compaction_limiter.throughput()
+    }
+
+    /// Accepts anything convertible into a `String`, so literals work directly and owned strings
+    /// are moved in without being copied.
+    ///
+    /// To keep `len` exact, every write first looks its key up the way `read` does. For new keys the
+    /// bloom filters usually end that early, but overwriting a key that was flushed reads its segment.
+    /// `benches/read_path.rs` measures both.
+    pub fn write<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        check_user_key(&key)?;
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.write(&key, value.len())?;
+        }
+        self.put(key, Some(value))
+    }
+
+    /// Logs and applies a write without any of the checks on user keys, deleting the key on `None`.
+    /// Engine-internal records are written through here.
+    fn put(&mut self, key: String, value: Option<String>) -> Result<()> {
+        self.put_record(KVPair { key, value, expires_at: None })
+    }
+
+    /// Same as `put`, keeping the record's expiry.
+    fn put_record(&mut self, kv: KVPair) -> Result<()> {
+        if let Some(wal) = self.wal.as_mut() {
+            wal.append(&kv)?;
+        }
+        self.apply(kv)?;
         Ok(())
     }
 
-    pub fn write(&mut self, key: String, value: String) -> Result<()> {
-        self.write_to_wal(&key, &value)?;
-        self.bloom_filter.insert(&key);
+    /// Puts an already logged write into the memtable, flushing first if it's full.
+    /// Returns whether the key held a live value beforehand, keeping the live key count up to date.
+    fn apply(&mut self, kv: KVPair) -> Result<bool> {
+        let newest = self.newest_record(&kv.key)?;
+        self.apply_known(kv, &newest)?;
+        Ok(newest.as_ref().and_then(StoredValue::live).is_some())
+    }
+
+    /// Same as `apply`, for callers that have already looked up the key's newest record.
+    fn apply_known(&mut self, kv: KVPair, newest: &Option<StoredValue>) -> Result<()> {
+        //expired values count until a merge drops them, see `len`
+        let had_value = matches!(newest, Some(StoredValue { value: Some(_), .. }));
+        let (key, stored) = kv.into_entry();
+        if !is_reserved(&key) {
+            if stored.value.is_none() {
+                self.op_counts.deletes += 1;
+            } else {
+                self.op_counts.writes += 1;
+            }
+            match (had_value, stored.value.is_none()) {
+                (false, false) => self.live_keys += 1,
+                (true, true) => self.live_keys -= 1,
+                _ => {}
+            }
+        }
         if self.memtable.at_capacity() && !self.memtable.contains(&key) {
-            let new_segment = self.flush_memtable()?;
-            self.segments.push(new_segment);
-            self.memtable.insert(key, value);
-            self.merge_segments()?;
-        } else {
-            self.memtable.insert(key, value);
+            self.flush_and_merge()?;
+        }
+        self.memtable.insert(key, stored);
+        Ok(())
+    }
+
+    /// Writes the memtable out to segments and merges them, exactly as a write arriving at a full
+    /// memtable would. Returns how many entries were flushed. With an empty memtable nothing is
+    /// written, so this is safe to call at any time, e.g. before taking a filesystem snapshot.
+    pub fn flush(&mut self) -> Result<u64> {
+        let entries = self.memtable.len() as u64;
+        self.flush_and_merge()?;
+        Ok(entries)
+    }
+
+    /// Dumps the memtable into a new segment and compacts. Does nothing if the memtable is empty.
+    /// If the merge fails, the flushed entries go back into the memtable and the segments are left as they were.
+    fn flush_and_merge(&mut self) -> Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let merged_segments = self.segments.len();
+        let flushed = self.flush_memtable()?;
+        self.segments.extend(flushed);
+        if let Err(e) = self.merge_segments() {
+            for mut segment in self.segments.split_off(merged_segments) {
+                for kv in segment.read_from_start()? {
+                    let (key, stored) = kv?.into_entry();
+                    self.memtable.insert(key, stored);
+                }
+            }
+            return Err(e);
         }
         Ok(())
     }
 
     pub fn write_to_wal(&mut self, key: &String, value: &String) -> Result<()> {
+        check_user_key(key)?;
         if self.wal.is_some() {
-            self.wal.as_mut().unwrap().persist(KVPair { key: key.clone(), value: value.clone() })?;
+            self.wal.as_mut().unwrap().append(&KVPair { key: key.clone(), value: Some(value.clone()), expires_at: None })?;
         }
         Ok(())
     }
 
+    /// Where to start looking for `key` on disk: the position of the biggest indexed key
+    /// less than or equal to it.
+    fn closest_index_entry(&self, key: &str) -> Option<(KeyOffset, SegmentIndex)> {
+        self.sparse_memory_index
+            .range::<str, _>((Unbounded, Included(key)))
+            .next_back()
+            .map(|(_, entry)| *entry)
+    }
+
     ///Unfortunately this is marked as mutable since relies on rust's seek api, which is also
     /// mutable. In the future, this might change to immutable if the seek api changes
     /// or if the issue becomes significant enough to warrant  using `Rc<RefCell<>>`
     pub fn read(&mut self, key: &str) -> Result<Option<String>> {
-        if let Some(value) = self.memtable.get(key) {
-            if value == &*TOMBSTONE_VALUE {
-                return Ok(None);
-            }
-            return Ok(Some(value.to_owned()));
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.read(key)?;
         }
+        self.op_counts.reads += 1;
+        if is_reserved(key) {
+            return Ok(None);
+        }
+        self.read_record(key)
+    }
 
+    /// Looks a key up without hiding engine-internal records.
+    fn read_record(&mut self, key: &str) -> Result<Option<String>> {
+        Ok(self.newest_record(key)?.as_ref().and_then(StoredValue::live).map(str::to_owned))
+    }
 
-        //get the biggest element less than or equal to the key
-        let mut before = self.sparse_memory_index.range((Unbounded, Included(key.to_owned())));
-        let maybe_closest_key = before.next_back();
-
-        if maybe_closest_key.is_none() {
-            return Ok(None);
+    /// The newest stored value of a key exactly as stored, so it may have expired.
+    /// A key whose newest record is a delete comes back without a value.
+    fn newest_record(&mut self, key: &str) -> Result<Option<StoredValue>> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(Some(value.clone()));
         }
 
-        let (closest_key, (key_offset, segment_index)) = maybe_closest_key.unwrap();
 
-        for index in *segment_index..self.segments.len() {
+        let (key_offset, segment_index) = match self.closest_index_entry(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        for index in segment_index..self.segments.len() {
             let segment = &mut self.segments[index];
-            let maybe_value = if index == *segment_index { segment.search_from(key, *key_offset)? } else { segment.search_from_start(key)? };
+            if !segment.may_contain(key) {
+                continue;
+            }
+            let maybe_value = if index == segment_index { segment.search_from(key, key_offset)? } else { segment.search_from_start(key)? };
             if maybe_value.is_some() {
-                if maybe_value.as_ref().map(|x| x != &*TOMBSTONE_VALUE).unwrap() { return Ok(maybe_value); };
-
-                //if it's marked with a tombstone value, it's a "deleted" key
-                return Ok(None);
+                return Ok(maybe_value);
             }
         }
 
         Ok(None)
     }
-    pub fn delete(&mut self, key: &str) -> Result<()> {
-        if self.wal.is_some() {
-            self.wal.as_mut().unwrap().persist(KVPair { key: key.to_owned(), value: TOMBSTONE_VALUE.to_string() })?;
+
+    /// Deletes `key` and returns whether it held a live value. A key with nothing stored, or whose
+    /// newest record is already a delete, is left alone, so nothing is written to the WAL or segments.
+    pub fn delete<K: Into<String>>(&mut self, key: K) -> Result<bool> {
+        let key = key.into();
+        check_user_key(&key)?;
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.delete(&key)?;
         }
-        self.write(key.to_owned(), TOMBSTONE_VALUE.to_string())?;
-        Ok(())
+        let newest = self.newest_record(&key)?;
+        let existed = newest.as_ref().and_then(StoredValue::live).is_some();
+        //expired values are still stored, so they get a tombstone like any other
+        if !matches!(newest, Some(StoredValue { value: Some(_), .. })) {
+            return Ok(false);
+        }
+        let kv = KVPair { key, value: None, expires_at: None };
+        if let Some(wal) = self.wal.as_mut() {
+            wal.append(&kv)?;
+        }
+        self.apply_known(kv, &newest)?;
+        Ok(existed)
     }
 
-    pub fn contains(&mut self, key: &str) -> Result<bool> {
-        if !self.bloom_filter.contains(&key) {
+    /// Same as `delete`, but writes the delete without first looking for the key.
+    pub fn delete_unchecked<K: Into<String>>(&mut self, key: K) -> Result<()> {
+        let key = key.into();
+        check_user_key(&key)?;
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.delete(&key)?;
+        }
+        self.put(key, None)
+    }
+
+    /// Passes the current value of `key` to `f` and stores whatever it returns, deleting the key on `None`.
+    /// The key is looked up once and the result logged as a single WAL record. A result equal to the
+    /// old value is still written. Returns the new value.
+    pub fn update<K: Into<String>, F: FnOnce(Option<&str>) -> Option<String>>(&mut self, key: K, f: F) -> Result<Option<String>> {
+        let key = key.into();
+        check_user_key(&key)?;
+        let newest = self.newest_record(&key)?;
+        let new = f(newest.as_ref().and_then(StoredValue::live));
+        if let Some(tracer) = self.tracer.as_mut() {
+            match &new {
+                Some(value) => tracer.write(&key, value.len())?,
+                None => tracer.delete(&key)?,
+            }
+        }
+        let kv = KVPair { key, value: new.clone(), expires_at: None };
+        if let Some(wal) = self.wal.as_mut() {
+            wal.append(&kv)?;
+        }
+        self.apply_known(kv, &newest)?;
+        Ok(new)
+    }
+
+    /// Like `write`, but returns the live value it replaced, `None` if the key was absent, deleted or expired.
+    /// `write` looks the key up anyway to keep `len` exact, so this costs no extra read.
+    pub fn insert_and_fetch_prev<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) -> Result<Option<String>> {
+        let value = value.into();
+        let mut previous = None;
+        self.update(key, |current| {
+            previous = current.map(str::to_owned);
+            Some(value)
+        })?;
+        Ok(previous)
+    }
+
+    /// Returns the value stored under `key`. If there is none, stores the result of `f` and returns that,
+    /// so `f` only runs for absent or deleted keys.
+    pub fn get_or_insert_with<K: Into<String>, F: FnOnce() -> String>(&mut self, key: K, f: F) -> Result<String> {
+        let key = key.into();
+        check_user_key(&key)?;
+        if let Some(value) = self.read_record(&key)? {
+            return Ok(value);
+        }
+        let value = f();
+        self.write(key, value.clone())?;
+        Ok(value)
+    }
+
+    /// Sets `key` to `new`, or deletes it if `new` is `None`, but only if its current value is `expected`.
+    /// An `expected` of `None` means the key must be absent. Returns whether the swap happened.
+    /// Nothing else can write in between since this holds `&mut self` throughout.
+    pub fn compare_and_swap<K: Into<String>>(&mut self, key: K, expected: Option<&str>, new: Option<String>) -> Result<bool> {
+        let key = key.into();
+        check_user_key(&key)?;
+        if self.read_record(&key)?.as_deref() != expected {
             return Ok(false);
         }
+        match new {
+            Some(value) => self.write(key, value)?,
+            None => self.delete_unchecked(key)?,
+        }
+        Ok(true)
+    }
+
+    /// Deletes all of `keys`, logging them as one WAL record holding just the key list.
+    /// Returns how many of the keys existed beforehand, which costs a lookup per key like `write`.
+    pub fn delete_many<I: IntoIterator<Item=String>>(&mut self, keys: I) -> Result<u64> {
+        self.delete_batch(keys.into_iter().collect())
+    }
+
+    /// Same as `delete_many`, without the count.
+    pub fn delete_many_unchecked<I: IntoIterator<Item=String>>(&mut self, keys: I) -> Result<()> {
+        self.delete_batch(keys.into_iter().collect())?;
+        Ok(())
+    }
+
+    fn delete_batch(&mut self, keys: Vec<String>) -> Result<u64> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        for key in keys.iter() {
+            check_user_key(key)?;
+        }
+        if let Some(tracer) = self.tracer.as_mut() {
+            for key in keys.iter() {
+                tracer.delete(key)?;
+            }
+        }
+        if let Some(wal) = self.wal.as_mut() {
+            wal.append_delete_many(&keys)?;
+        }
+        let mut existing = 0;
+        for key in keys {
+            if self.apply(KVPair { key, value: None, expires_at: None })? {
+                existing += 1;
+            }
+        }
+        Ok(existing)
+    }
+
+    /// Number of live keys. Kept up to date on every write and delete, so this doesn't scan anything;
+    /// the cost is a lookup of the key on each of them instead, see `write`.
+    /// Keys whose TTL has run out keep counting until a merge drops them.
+    pub fn len(&self) -> u64 {
+        self.live_keys
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live_keys == 0
+    }
+
+    /// Segments whose bloom filter rules `key` out are skipped without touching their files,
+    /// so a miss usually doesn't read from disk at all.
+    pub fn contains(&mut self, key: &str) -> Result<bool> {
         let maybe_value = self.read(key)?;
         return Ok(maybe_value.is_some());
     }
@@ -319,19 +803,20 @@ impl LSMEngine {
 
 impl Default for LSMEngine {
     fn default() -> Self {
-        return LSMBuilder::new().build();
+        return LSMBuilder::new().build().expect("an engine without a WAL can always be built");
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{LSMEngine, LSMBuilder};
-    use crate::{TOMBSTONE_VALUE};
+    use crate::{LSMEngine, LSMBuilder, Error, FileId};
+    use crate::kv::KVPair;
     use rand::seq::SliceRandom;
-    use rand::{SeedableRng};
+    use rand::{Rng, SeedableRng};
 
     use rand::rngs::StdRng;
-    use std::collections::{HashMap};
+    use std::collections::{HashMap, BTreeMap};
+    use std::fs::File;
 
 
     #[test]
@@ -341,10 +826,10 @@ mod tests {
             segment_size(100).
             sparse_offset(2).
             inmemory_capacity(3).
-            build();
-        lsm.write("k1".to_owned(), "v1".to_owned())?;
-        lsm.write("k2".to_owned(), "v2".to_owned())?;
-        lsm.write("k3".to_owned(), "v3".to_owned())?;
+            build()?;
+        lsm.write("k1", "v1")?;
+        lsm.write("k2", "v2")?;
+        lsm.write("k3", "v3")?;
 
         for (k, v) in vec![("k1", "v1"), ("k2", "v2"), ("k3", "v3")] {
             assert_eq!(lsm.read(k)?, Some(v.to_owned()));
@@ -360,9 +845,9 @@ mod tests {
             .segment_size(2)
             .inmemory_capacity(1)
             .sparse_offset(2)
-            .build();
-        lsm.write("k1".to_owned(), "v1".to_owned())?;
-        lsm.write("k2".to_owned(), "v2".to_owned())?;
+            .build()?;
+        lsm.write("k1", "v1")?;
+        lsm.write("k2", "v2")?;
         lsm.delete("k1")?;
         let value = lsm.read("k1")?;
         assert!(value.is_none());
@@ -377,12 +862,12 @@ mod tests {
             segment_size(2).
             inmemory_capacity(1).
             sparse_offset(2).
-            build();
+            build()?;
 
-        lsm.write("k1".to_owned(), "v1".to_owned())?;
-        lsm.write("k2".to_owned(), "k2".to_owned())?;
-        lsm.write("k1".to_owned(), "v_1_1".to_owned())?;
-        lsm.write("k3".to_owned(), "v3".to_owned())?;
+        lsm.write("k1", "v1")?;
+        lsm.write("k2", "k2")?;
+        lsm.write("k1", "v_1_1")?;
+        lsm.write("k3", "v3")?;
 
         let value = lsm.read("k1")?;
         assert_eq!(value, Some("v_1_1".to_owned()));
@@ -415,11 +900,11 @@ mod tests {
 
     #[test]
     fn test_recovery_with_wal() -> std::result::Result<(), Box<dyn std::error::Error>> {
-        let mut lsm = LSMBuilder::new().wal_path("foo").build();
+        let mut lsm = LSMBuilder::new().wal_path("foo").build()?;
         let dataset: Vec<_> = (0..20).map(|i| ("k".to_owned() + &i.to_string(), "v".to_owned() + &i.to_string())).collect();
 
         for (key, v) in dataset.iter() {
-            lsm.write(key.to_string(), v.to_string())?;
+            lsm.write(key, v)?;
         }
 
 
@@ -428,7 +913,7 @@ mod tests {
             lsm.delete(k)?;
         }
 
-        let mut new_lsm = LSMBuilder::new().build();
+        let mut new_lsm = LSMBuilder::new().build()?;
         new_lsm.recover_from(lsm.wal.unwrap().file)?;
         for i in 0..10 {
             let (k, v) = &dataset[i];
@@ -446,11 +931,703 @@ mod tests {
 
     #[test]
     fn test_contains() -> std::result::Result<(), Box<dyn std::error::Error>> {
-        let mut lsm = LSMBuilder::new().inmemory_capacity(1).build();
-        lsm.write("k1".to_owned(), "v1".to_owned())?;
+        let mut lsm = LSMBuilder::new().inmemory_capacity(1).build()?;
+        lsm.write("k1", "v1")?;
         lsm.delete("k1")?;
         assert_eq!(lsm.contains("k1")?, false);
         assert_eq!(lsm.contains("k2")?, false);
         Ok(())
     }
+
+    #[test]
+    fn test_wal_path_is_created_when_missing() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+        assert!(path.exists());
+        lsm.write("k1", "v1")?;
+        assert!(std::fs::metadata(&path)?.len() > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_path_with_empty_file() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        File::create(&path)?;
+        let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+        assert_eq!(lsm.read("k1")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_path_is_a_directory() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let result = LSMBuilder::new().wal_path(dir.path()).build();
+        match result {
+            Err(Error::InvalidWalPath { path }) => assert_eq!(path, dir.path()),
+            _ => panic!("expected InvalidWalPath"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_path_on_missing_volume() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("not_mounted").join("wal");
+        match LSMBuilder::new().wal_path(&path).build() {
+            Err(Error::WalUnavailable { path: reported, .. }) => assert_eq!(reported, path),
+            _ => panic!("expected WalUnavailable"),
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_wal_path_unreadable() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        File::create(&path)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o000))?;
+
+        //permissions aren't enforced for root, so there's nothing to check
+        if std::fs::OpenOptions::new().read(true).write(true).open(&path).is_ok() {
+            return Ok(());
+        }
+        match LSMBuilder::new().wal_path(&path).build() {
+            Err(Error::WalUnavailable { path: reported, .. }) => assert_eq!(reported, path),
+            _ => panic!("expected WalUnavailable"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_path_with_content_is_recovered() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        {
+            let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(2).build()?;
+            for i in 0..10 {
+                lsm.write(format!("k{}", i), format!("v{}", i))?;
+            }
+            lsm.delete("k3")?;
+        }
+
+        let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+        for i in 0..10 {
+            let expected = if i == 3 { None } else { Some(format!("v{}", i)) };
+            assert_eq!(lsm.read(&format!("k{}", i))?, expected);
+        }
+
+        //writes after recovery are appended, so a second recovery sees both
+        lsm.write("k10", "v10")?;
+        let mut recovered = LSMBuilder::new().wal_path(&path).build()?;
+        assert_eq!(recovered.read("k1")?, Some("v1".to_owned()));
+        assert_eq!(recovered.read("k10")?, Some("v10".to_owned()));
+
+        let mut not_recovered = LSMBuilder::new().wal_path(&path).recover_wal(false).build()?;
+        assert_eq!(not_recovered.read("k1")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_segment_stats() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(10).segment_size(100).build()?;
+        for i in 0..10 {
+            lsm.write(format!("k{}", i), format!("v{}", i))?;
+        }
+        for i in 0..5 {
+            lsm.delete(format!("k{}", i))?;
+        }
+        //the next new key flushes the memtable
+        lsm.write("k_flush", "v")?;
+        let stats = lsm.segment_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].live_records, 5);
+        assert_eq!(stats[0].tombstones, 5);
+        assert_eq!(stats[0].shadowed_dropped, 0);
+
+        //overwrite three of the flushed keys and flush again
+        for i in 5..8 {
+            lsm.write(format!("k{}", i), "new")?;
+        }
+        //k_flush, the three overwrites and six fillers fill the memtable, the seventh filler flushes it
+        for i in 0..7 {
+            lsm.write(format!("filler{}", i), "v")?;
+        }
+        let stats = lsm.segment_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].shadowed_dropped, 3);
+        assert_eq!(stats[0].tombstones, 5);
+        assert_eq!(stats[0].live_records, 5 + 1 + 6);
+        for i in 5..8 {
+            assert_eq!(lsm.read(&format!("k{}", i))?, Some("new".to_owned()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_creation_syncs_directory() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let syncs = || crate::fsync::DIR_SYNCS.with(|count| count.get());
+        let dir = tempfile::tempdir()?;
+
+        let before = syncs();
+        LSMBuilder::new().persist_data(true).wal_path(dir.path().join("wal")).build()?;
+        assert_eq!(syncs(), before + 1);
+
+        //reopening an existing file doesn't change the directory
+        LSMBuilder::new().persist_data(true).wal_path(dir.path().join("wal")).build()?;
+        assert_eq!(syncs(), before + 1);
+
+        LSMBuilder::new().wal_path(dir.path().join("wal_2")).build()?;
+        assert_eq!(syncs(), before + 1);
+
+        LSMBuilder::new().durable_renames(true).wal_path(dir.path().join("wal_3")).build()?;
+        assert_eq!(syncs(), before + 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_segments_get_sized_bloom_filters() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new()
+            .inmemory_capacity(10)
+            .segment_size(25)
+            .bloom_false_positive_rate(0.001)
+            .build()?;
+        for i in 0..100 {
+            lsm.write(format!("k{}", i), format!("v{}", i))?;
+        }
+        let stats = lsm.segment_stats();
+        assert_eq!(stats.len(), 4);
+        for segment in stats {
+            assert!(segment.bloom_bits_per_key > 14.0);
+            assert!(segment.bloom_fp_rate < 0.01);
+        }
+        for i in 0..100 {
+            assert!(lsm.contains(&format!("k{}", i))?);
+            assert!(!lsm.contains(&format!("missing{}", i))?);
+        }
+        assert!(LSMBuilder::new().bloom_false_positive_rate(1.0).build().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_clear() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        {
+            let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(2).segment_size(2).build()?;
+            for i in 0..5 {
+                lsm.write(format!("k{}", i), "v")?;
+            }
+            lsm.clear()?;
+            assert!(lsm.is_empty());
+            assert_eq!(lsm.read("k4")?, None);
+            assert_eq!(lsm.keys()?.collect::<Result<Vec<_>, _>>()?.len(), 0);
+            lsm.write("after", "v")?;
+        }
+        {
+            let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+            assert_eq!(lsm.keys()?.collect::<Result<Vec<_>, _>>()?, vec!["after".to_owned()]);
+            assert_eq!(lsm.len(), 1);
+            lsm.clear()?;
+        }
+        let mut lsm = LSMBuilder::new().build()?;
+        lsm.recover_from(File::open(&path)?)?;
+        assert!(lsm.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(10).segment_size(4).build()?;
+        assert_eq!(lsm.flush()?, 0);
+        assert!(lsm.segments.is_empty());
+        for i in 0..6 {
+            lsm.write(format!("k{}", i), "v")?;
+        }
+        lsm.delete("k0")?;
+        assert_eq!(lsm.flush()?, 6);
+        assert!(lsm.memtable.is_empty());
+        assert_eq!(lsm.segments.iter().map(|s| s.size()).collect::<Vec<_>>(), vec![4, 2]);
+        assert_eq!(lsm.flush()?, 0);
+        assert_eq!(lsm.segments.len(), 2);
+        assert_eq!(lsm.read("k5")?, Some("v".to_owned()));
+        assert_eq!(lsm.read("k0")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bloom_bits_per_key() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(1000).segment_size(1000).bloom_bits_per_key(6.0).build()?;
+        for i in 0..3001 {
+            lsm.write(format!("k{}", i), "v")?;
+        }
+        lsm.delete("k3")?;
+        assert!(lsm.contains("k4")?);
+        assert!(!lsm.contains("k3")?);
+        //6 bits per key gives a false positive rate of about 5.6%
+        for segment in lsm.segments.iter() {
+            let absent = (0..10_000).filter(|i| segment.may_contain(&format!("absent{}", i))).count();
+            assert!(absent <= 1000, "{} false positives", absent);
+        }
+        assert!(LSMBuilder::new().bloom_bits_per_key(0.0).build().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_identities() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        std::fs::write(&path, "{\"key\":\"k1\",\"value\":\"v1\"}\nnot json\n{\"key\":\"k2\",\"value\":\"v2\"}\n")?;
+        match LSMBuilder::new().wal_path(&path).build() {
+            Err(error @ Error::WalCorrupted { .. }) => assert!(error.to_string().contains(&path.display().to_string())),
+            _ => panic!("expected WalCorrupted"),
+        }
+
+        let mut lsm = LSMBuilder::new().inmemory_capacity(2).segment_size(2).build()?;
+        for i in 0..5 {
+            lsm.write(format!("k{}", i), "v")?;
+        }
+        let files: Vec<_> = lsm.segment_stats().into_iter().map(|stats| stats.file).collect();
+        assert_eq!(files.len(), 2);
+        assert_ne!(files[0], files[1]);
+        for file in files {
+            assert!(matches!(file, FileId::Temp(_)));
+            assert!(file.to_string().starts_with("temp-"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_many() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        {
+            let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(3).segment_size(3).build()?;
+            for i in 0..6 {
+                lsm.write(format!("k{}", i), format!("v{}", i))?;
+            }
+            let keys = vec!["k1", "k4", "k5", "missing", "k1"];
+            assert_eq!(lsm.delete_many(keys.into_iter().map(String::from))?, 3);
+            for (key, expected) in &[("k0", true), ("k1", false), ("k2", true), ("k4", false), ("k5", false)] {
+                assert_eq!(lsm.contains(key)?, *expected, "{}", key);
+            }
+            lsm.delete_many_unchecked(vec!["k2".to_owned()])?;
+            assert!(matches!(lsm.delete_many(vec![format!("{}x", crate::RESERVED_KEY_PREFIX)]), Err(Error::ReservedKey { .. })));
+        }
+
+        let mut recovered = LSMBuilder::new().wal_path(&path).inmemory_capacity(3).segment_size(3).build()?;
+        assert_eq!(recovered.recovery_report().unwrap().records_replayed, 8);
+        for (key, expected) in &[("k0", true), ("k1", false), ("k2", false), ("k3", true), ("k4", false), ("k5", false)] {
+            assert_eq!(recovered.contains(key)?, *expected, "{}", key);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_many_wal_volume() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let keys: Vec<_> = (0..10_000).map(|i| format!("key{:05}", i)).collect();
+
+        let one_by_one = dir.path().join("one_by_one");
+        let mut lsm = LSMBuilder::new().wal_path(&one_by_one).build()?;
+        for key in keys.iter() {
+            lsm.delete_unchecked(key.as_str())?;
+        }
+        let batched = dir.path().join("batched");
+        let mut lsm = LSMBuilder::new().wal_path(&batched).build()?;
+        lsm.delete_many_unchecked(keys.iter().cloned())?;
+
+        let one_by_one = std::fs::metadata(one_by_one)?.len();
+        let batched = std::fs::metadata(batched)?.len();
+        //each separate record repeats the op and a checksum
+        assert!(one_by_one - batched > 10_000 * 30);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reserved_keys() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let internal = format!("{}counter", crate::RESERVED_KEY_PREFIX);
+        let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(2).segment_size(2).build()?;
+
+        assert!(matches!(lsm.write(internal.as_str(), "1"), Err(Error::ReservedKey { .. })));
+        assert!(matches!(lsm.delete(internal.as_str()), Err(Error::ReservedKey { .. })));
+        assert!(matches!(lsm.write_to_wal(&internal, &"1".to_owned()), Err(Error::ReservedKey { .. })));
+        //only the prefix is reserved
+        lsm.write(format!("user{}", crate::RESERVED_KEY_PREFIX), "v")?;
+
+        lsm.put(internal.clone(), Some("1".to_owned()))?;
+        for tier in &["memtable", "segment"] {
+            assert_eq!(lsm.read(&internal)?, None, "{}", tier);
+            assert!(!lsm.contains(&internal)?);
+            assert_eq!(lsm.head(&internal)?, None);
+            assert_eq!(lsm.read_record(&internal)?, Some("1".to_owned()));
+            lsm.write("a", "v")?;
+            lsm.write("b", "v")?;
+        }
+        assert_eq!(lsm.warm_up_recent(10, &crate::WarmupOptions::default())?.keys_read, 3);
+
+        let mut recovered = LSMBuilder::new().wal_path(&path).build()?;
+        assert_eq!(recovered.read(&internal)?, None);
+        assert_eq!(recovered.read_record(&internal)?, Some("1".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_splits_at_segment_size() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(15).segment_size(3).sparse_offset(2).build()?;
+        for i in 0..14 {
+            lsm.write(format!("k{:02}", i), format!("v{:02}", i))?;
+        }
+        assert_eq!(lsm.segments.len(), 0);
+
+        let flushed = lsm.flush_memtable()?;
+        assert_eq!(flushed.len(), 5);
+        let sizes: Vec<_> = flushed.iter().map(|s| s.size()).collect();
+        assert_eq!(sizes, vec![3, 3, 3, 3, 2]);
+        lsm.segments.extend(flushed);
+        lsm.merge_segments()?;
+
+        assert!(lsm.segments.iter().all(|s| s.size() <= 3));
+        for i in 0..14 {
+            assert_eq!(lsm.read(&format!("k{:02}", i))?, Some(format!("v{:02}", i)));
+        }
+
+        //filling the memtable again flushes through the normal write path
+        for i in 14..30 {
+            lsm.write(format!("k{:02}", i), format!("v{:02}", i))?;
+        }
+        assert_eq!(lsm.segments.len(), 10);
+        for i in 0..30 {
+            assert_eq!(lsm.read(&format!("k{:02}", i))?, Some(format!("v{:02}", i)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_sparse_index_offsets_beyond_4gb() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        use std::io::{Seek, SeekFrom};
+        let mut lsm = LSMBuilder::new().build()?;
+
+        let mut file = tempfile::tempfile()?;
+        let start = 6 * (1u64 << 30);
+        file.seek(SeekFrom::Start(start))?;
+        let mut segment = crate::Segment::with_file(file, FileId::temp());
+        let mut offsets = vec![];
+        for i in 0..5 {
+            offsets.push(segment.write(KVPair { key: format!("k{}", i), value: Some(format!("v{}", i)), expires_at: None })?);
+        }
+        segment.seal(0.01);
+        lsm.segments.push(segment);
+        lsm.sparse_memory_index.insert("k0".to_owned(), (offsets[0], 0));
+        lsm.sparse_memory_index.insert("k3".to_owned(), (offsets[3], 0));
+
+        for i in 0..5 {
+            assert_eq!(lsm.read(&format!("k{}", i))?, Some(format!("v{}", i)));
+        }
+        assert!(offsets.iter().all(|offset| *offset >= start));
+        Ok(())
+    }
+
+    #[test]
+    fn test_paced_compaction() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dataset: Vec<_> = (0..40).map(|i| (format!("k{:03}", i), format!("v{:03}", i))).collect();
+        let mut lsm = LSMBuilder::new()
+            .inmemory_capacity(10)
+            .segment_size(10)
+            .compaction_rate_limit(4000)
+            .compaction_burst(100)
+            .build()?;
+        assert_eq!(lsm.compaction_rate_limit(), Some(4000));
+
+        let start = std::time::Instant::now();
+        for (k, v) in dataset.iter() {
+            lsm.write(k.clone(), v.clone())?;
+            assert_eq!(lsm.read(k)?, Some(v.clone()));
+        }
+        //three merges moving 10, 20 and 30 records (8 bytes each) in and out: 960 bytes
+        assert!(start.elapsed() >= std::time::Duration::from_millis(180));
+        assert!(lsm.compaction_throughput() > 0.0);
+
+        lsm.set_compaction_rate_limit(None);
+        assert_eq!(lsm.compaction_rate_limit(), None);
+        for (k, v) in dataset.iter() {
+            assert_eq!(lsm.read(k)?, Some(v.clone()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_len() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        {
+            let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(3).segment_size(3).build()?;
+            assert!(lsm.is_empty());
+            for i in 0..10 {
+                lsm.write(format!("k{}", i), "v")?;
+            }
+            //overwrites of keys in the memtable and in segments
+            lsm.write("k9", "again")?;
+            lsm.write("k0", "again")?;
+            lsm.delete("k1")?;
+            lsm.delete("k1")?;
+            lsm.delete("missing")?;
+            assert_eq!(lsm.delete_many(vec!["k2".to_owned(), "k2".to_owned(), "nope".to_owned()])?, 1);
+            lsm.put(format!("{}internal", crate::RESERVED_KEY_PREFIX), Some("v".to_owned()))?;
+            assert_eq!(lsm.len(), 8);
+            assert_eq!(lsm.len(), lsm.keys()?.collect::<Result<Vec<_>, _>>()?.len() as u64);
+        }
+
+        let lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(3).segment_size(3).build()?;
+        assert_eq!(lsm.len(), 8);
+        assert!(!lsm.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_len_matches_keys_across_recovery() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let mut rng = StdRng::seed_from_u64(257);
+        for round in 0..3 {
+            let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(5).segment_size(4).build()?;
+            assert_eq!(lsm.len(), lsm.keys()?.collect::<Result<Vec<_>, _>>()?.len() as u64, "after recovery in round {}", round);
+            for _ in 0..200 {
+                let key = format!("k{}", rng.gen_range(0, 30));
+                match rng.gen_range(0, 3) {
+                    0 => {
+                        lsm.delete(key)?;
+                    }
+                    1 => {
+                        lsm.delete_many(vec![key, format!("k{}", rng.gen_range(0, 30))])?;
+                    }
+                    _ => lsm.write(key, "v")?,
+                }
+            }
+            assert_eq!(lsm.len(), lsm.keys()?.collect::<Result<Vec<_>, _>>()?.len() as u64, "after writes in round {}", round);
+            assert_eq!(lsm.check_invariants(), Ok(()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_non_ascii_keys_match_model() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        //pieces whose byte order differs from what a naive char or UTF-16 comparison would give:
+        //precomposed vs combining accents, BMP vs astral codepoints, and the top of the key space
+        const PIECES: [&str; 14] = ["a", "z", "\u{7F}", "\u{80}", "é", "e\u{301}", "ß", "中", "文", "テ", "한", "\u{FFFD}", "𝄞", "\u{10FFFD}"];
+        fn random_key(rng: &mut StdRng) -> String {
+            (0..rng.gen_range(1, 4)).map(|_| *PIECES.choose(rng).unwrap()).collect()
+        }
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let mut rng = StdRng::seed_from_u64(272);
+        let mut model = BTreeMap::new();
+        for round in 0..3 {
+            let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(5).segment_size(4).sparse_offset(2).build()?;
+            for _ in 0..120 {
+                let key = random_key(&mut rng);
+                if rng.gen_range(0, 4) == 0 {
+                    lsm.delete(key.as_str())?;
+                    model.remove(&key);
+                } else {
+                    let value = format!("{}-{}", key, rng.gen_range(0, 100));
+                    lsm.write(key.as_str(), value.as_str())?;
+                    model.insert(key, value);
+                }
+            }
+
+            let everything = lsm.range::<String, _>(..)?.collect::<Vec<_>>();
+            assert_eq!(everything, model.clone().into_iter().collect::<Vec<_>>(), "round {}", round);
+            for _ in 0..30 {
+                let key = random_key(&mut rng);
+                assert_eq!(lsm.read(&key)?.as_ref(), model.get(&key), "read {:?} in round {}", key, round);
+
+                let (mut start, mut end) = (random_key(&mut rng), random_key(&mut rng));
+                if start > end {
+                    std::mem::swap(&mut start, &mut end);
+                }
+                let expected = model.range(start.clone()..end.clone()).map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>();
+                assert_eq!(lsm.range(start.as_str()..end.as_str())?.collect::<Vec<_>>(), expected, "range {:?}..{:?}", start, end);
+
+                let prefix = PIECES.choose(&mut rng).unwrap();
+                let expected = model.iter().filter(|(k, _)| k.starts_with(prefix)).map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>();
+                assert_eq!(lsm.scan_prefix(prefix)?.collect::<Vec<_>>(), expected, "prefix {:?}", prefix);
+            }
+            assert_eq!(lsm.len(), model.len() as u64);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_and_swap() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(2).segment_size(2).build()?;
+        assert!(lsm.compare_and_swap("k", None, Some("v1".to_owned()))?);
+        assert!(!lsm.compare_and_swap("k", None, Some("other".to_owned()))?);
+        for i in 0..4 {
+            lsm.write(format!("filler{}", i), "v")?;
+        }
+        //"k" now lives in a segment
+        assert!(lsm.memtable.get("k").is_none());
+        assert!(!lsm.compare_and_swap("k", Some("v0"), Some("v2".to_owned()))?);
+        assert!(lsm.compare_and_swap("k", Some("v1"), Some("v2".to_owned()))?);
+        assert_eq!(lsm.read("k")?, Some("v2".to_owned()));
+
+        assert!(!lsm.compare_and_swap("k", Some("v1"), None)?);
+        assert!(lsm.compare_and_swap("k", Some("v2"), None)?);
+        assert_eq!(lsm.read("k")?, None);
+        //a deleted key counts as absent
+        assert!(lsm.compare_and_swap("k", None, Some("v3".to_owned()))?);
+        assert_eq!(lsm.read("k")?, Some("v3".to_owned()));
+        assert!(lsm.compare_and_swap("missing", None, None)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_or_insert_with() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(2).segment_size(2).build()?;
+        let mut calls = 0;
+        assert_eq!(lsm.get_or_insert_with("k", || { calls += 1; "computed".to_owned() })?, "computed");
+        assert_eq!(lsm.get_or_insert_with("k", || { calls += 1; "again".to_owned() })?, "computed");
+        assert_eq!(calls, 1);
+
+        lsm.write("d", "v")?;
+        lsm.delete("d")?;
+        assert_eq!(lsm.get_or_insert_with("d", || "fresh".to_owned())?, "fresh");
+        assert!(lsm.get_or_insert_with(format!("{}x", crate::RESERVED_KEY_PREFIX), || "v".to_owned()).is_err());
+
+        let mut recovered = LSMBuilder::new().wal_path(&path).build()?;
+        assert_eq!(recovered.read("k")?, Some("computed".to_owned()));
+        assert_eq!(recovered.read("d")?, Some("fresh".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_reports_existence() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(2).segment_size(2).build()?;
+        for i in 0..5 {
+            lsm.write(format!("k{}", i), "v")?;
+        }
+        assert!(!lsm.memtable.contains("k0"));
+        assert!(lsm.delete("k0")?);
+        assert!(lsm.delete("k4")?);
+        let wal_len = std::fs::metadata(&path)?.len();
+        assert!(!lsm.delete("k0")?);
+        assert!(!lsm.delete("missing")?);
+        assert_eq!(std::fs::metadata(&path)?.len(), wal_len);
+        assert_eq!(lsm.stats()?.deletes, 2);
+
+        //an expired value no longer counts as existing, but still gets its tombstone
+        lsm.write_with_ttl("t", "v", std::time::Duration::from_millis(0))?;
+        assert_eq!(lsm.len(), 4);
+        assert!(!lsm.delete("t")?);
+        assert_eq!(lsm.len(), 3);
+        lsm.delete_unchecked("missing")?;
+        assert!(std::fs::metadata(&path)?.len() > wal_len);
+        assert_eq!(lsm.check_invariants(), Ok(()));
+
+        let mut recovered = LSMBuilder::new().wal_path(&path).build()?;
+        assert_eq!(recovered.keys()?.collect::<Result<Vec<_>, _>>()?, vec!["k1", "k2", "k3"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_and_fetch_prev() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(2).segment_size(2).build()?;
+        assert_eq!(lsm.insert_and_fetch_prev("k", "v1")?, None);
+        assert_eq!(lsm.insert_and_fetch_prev("k", "v2")?, Some("v1".to_owned()));
+        //push k out of the memtable so the previous value comes from a segment
+        for i in 0..4 {
+            lsm.write(format!("filler{}", i), "v")?;
+        }
+        assert!(!lsm.memtable.contains("k"));
+        assert_eq!(lsm.insert_and_fetch_prev("k", "v3")?, Some("v2".to_owned()));
+
+        lsm.delete("k")?;
+        assert_eq!(lsm.insert_and_fetch_prev("k", "v4")?, None);
+        lsm.write_with_ttl("t", "short", std::time::Duration::from_millis(0))?;
+        assert_eq!(lsm.insert_and_fetch_prev("t", "v")?, None);
+        assert!(lsm.insert_and_fetch_prev(format!("{}x", crate::RESERVED_KEY_PREFIX), "v").is_err());
+        assert_eq!(lsm.len(), 6);
+
+        let mut recovered = LSMBuilder::new().wal_path(&path).build()?;
+        assert_eq!(recovered.read("k")?, Some("v4".to_owned()));
+        assert_eq!(recovered.read("t")?, Some("v".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_index_with() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(10).segment_size(7).sparse_offset(5).build()?;
+        for i in 0..50 {
+            lsm.write(format!("k{:02}", i), format!("v{:02}", i))?;
+        }
+        let on_disk: usize = lsm.segments.iter().map(|s| s.size() as usize).sum();
+        let built_by_merge = lsm.sparse_memory_index.clone();
+        lsm.rebuild_index_with(5)?;
+        assert_eq!(lsm.sparse_memory_index, built_by_merge);
+
+        for (sparse_offset, expected_entries) in [(1, on_disk), (3, on_disk.div_ceil(3)), (100, 1)] {
+            lsm.rebuild_index_with(sparse_offset)?;
+            assert_eq!(lsm.sparse_memory_index.len(), expected_entries);
+            assert_eq!(lsm.config().sparse_offset, sparse_offset);
+            for i in 0..50 {
+                assert_eq!(lsm.read(&format!("k{:02}", i))?, Some(format!("v{:02}", i)));
+            }
+        }
+        assert!(matches!(lsm.rebuild_index_with(0), Err(Error::InvalidConfig(_))));
+        assert_eq!(lsm.sparse_memory_index.len(), 1);
+        assert_eq!(lsm.check_invariants(), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_update() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        {
+            let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(2).segment_size(2).build()?;
+            let increment = |current: Option<&str>| Some((current.map_or(0, |v| v.parse::<u64>().unwrap()) + 1).to_string());
+            assert_eq!(lsm.update("counter", increment)?, Some("1".to_owned()));
+            for i in 0..4 {
+                lsm.write(format!("filler{}", i), "v")?;
+            }
+            assert_eq!(lsm.update("counter", increment)?, Some("2".to_owned()));
+            assert_eq!(lsm.update("list", |current| Some(format!("{}a,", current.unwrap_or(""))))?, Some("a,".to_owned()));
+            assert_eq!(lsm.update("list", |current| Some(format!("{}b,", current.unwrap_or(""))))?, Some("a,b,".to_owned()));
+            assert_eq!(lsm.update("list", |_| None)?, None);
+            assert_eq!(lsm.update("list", |current| current.map(String::from))?, None);
+            assert_eq!(lsm.len(), 5);
+        }
+        //one record per update, plus the header and the fillers
+        assert_eq!(std::fs::read_to_string(&path)?.lines().count(), 1 + 4 + 6);
+
+        let mut recovered = LSMBuilder::new().wal_path(&path).build()?;
+        assert_eq!(recovered.read("counter")?, Some("2".to_owned()));
+        assert_eq!(recovered.read("list")?, None);
+        assert_eq!(recovered.len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_is_logged_once() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+        lsm.write("k", "v")?;
+        lsm.delete("k")?;
+        assert_eq!(std::fs::read_to_string(&path)?.lines().count(), 1 + 2);
+        Ok(())
+    }
 }
@@ -1,11 +1,14 @@
 use std::collections::BTreeMap;
-use std::collections::btree_map::IntoIter;
+use std::collections::btree_map::{IntoIter, Range};
+use std::ops::Bound;
 use std::hash::Hash;
 use std::borrow::Borrow;
 
 pub struct Memtable<K: PartialOrd + Hash + Ord, T> {
     kv_table: BTreeMap<K, T>,
     capacity: usize,
+    //key and value bytes held, kept up to date by `insert`
+    bytes: usize,
 }
 
 impl<K: PartialOrd + Hash + Ord, T> Memtable<K, T> {
@@ -13,14 +16,10 @@ impl<K: PartialOrd + Hash + Ord, T> Memtable<K, T> {
         Memtable {
             kv_table: BTreeMap::new(),
             capacity: capacity,
+            bytes: 0,
         }
     }
 
-
-    pub fn insert(&mut self, key: K, value: T) {
-        self.kv_table.insert(key, value);
-    }
-
     pub fn contains<Q: ?Sized>(&self, key: &Q) -> bool where K: Borrow<Q>, Q: Ord, {
         return self.kv_table.contains_key(key);
     }
@@ -33,15 +32,79 @@ impl<K: PartialOrd + Hash + Ord, T> Memtable<K, T> {
 
     pub fn clear(&mut self) {
         self.kv_table.clear();
+        self.bytes = 0;
     }
 
 
     pub fn drain(&mut self) -> IntoIter<K, T> {
+        self.bytes = 0;
         std::mem::replace(&mut self.kv_table, BTreeMap::new()).into_iter()
     }
 
+    /// Entries between the bounds, in key order. The bounds must not describe a decreasing range.
+    pub fn range<Q: ?Sized>(&self, bounds: (Bound<&Q>, Bound<&Q>)) -> Range<'_, K, T> where K: Borrow<Q>, Q: Ord, {
+        self.kv_table.range::<Q, _>(bounds)
+    }
+
     pub fn at_capacity(&self) -> bool {
-        self.kv_table.len() == self.capacity
+        self.kv_table.len() >= self.capacity
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kv_table.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.kv_table.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Key and value bytes held, not counting the tree's own overhead.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Shrinking below the current number of entries leaves the table over capacity
+    /// until its next drain.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+}
+
+/// How many bytes of data a key or value holds, for `Memtable::bytes`.
+pub trait ByteLen {
+    fn byte_len(&self) -> usize;
+}
+
+impl ByteLen for String {
+    fn byte_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl ByteLen for &str {
+    fn byte_len(&self) -> usize {
+        self.len()
+    }
+}
+
+//deletes hold no value
+impl<T: ByteLen> ByteLen for Option<T> {
+    fn byte_len(&self) -> usize {
+        self.as_ref().map(ByteLen::byte_len).unwrap_or(0)
+    }
+}
+
+impl<K: PartialOrd + Hash + Ord + ByteLen, T: ByteLen> Memtable<K, T> {
+    pub fn insert(&mut self, key: K, value: T) {
+        let key_len = key.byte_len();
+        self.bytes += key_len + value.byte_len();
+        if let Some(old) = self.kv_table.insert(key, value) {
+            self.bytes -= key_len + old.byte_len();
+        }
     }
 }
 
@@ -56,6 +119,34 @@ mod tests {
         memtable.insert("k1", "v1");
         assert_eq!(memtable.get("k1"), Some(&"v1"));
     }
+
+    #[test]
+    fn test_capacity() {
+        let mut memtable = Memtable::new(2);
+        memtable.insert("k1", "v1");
+        memtable.insert("k2", "v2");
+        assert!(memtable.at_capacity());
+        memtable.set_capacity(1);
+        assert!(memtable.at_capacity());
+        memtable.set_capacity(3);
+        assert!(!memtable.at_capacity());
+    }
+
+    #[test]
+    fn test_bytes() {
+        let mut memtable = Memtable::new(5);
+        memtable.insert("k1", "value");
+        memtable.insert("k2", "v");
+        assert_eq!(memtable.bytes(), 7 + 3);
+        memtable.insert("k1", "v");
+        assert_eq!(memtable.bytes(), 3 + 3);
+        let mut deletes = Memtable::new(5);
+        deletes.insert("k1", Some("v"));
+        deletes.insert("k1", None);
+        assert_eq!(deletes.bytes(), 2);
+        memtable.drain();
+        assert_eq!(memtable.bytes(), 0);
+    }
 }
 
 
@@ -0,0 +1,313 @@
+use crate::{LSMEngine, Result, Error};
+use crate::wal::{Wal, StopReason};
+use crate::kv::{KVPair, KvError};
+use crate::memtable::ByteLen;
+
+
+/// What happened while the engine replayed its WAL on startup.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RecoveryReport {
+    pub records_replayed: u64,
+    /// bytes of the WAL, header included, that were read back intact
+    pub verified_bytes: u64,
+    /// size of the WAL file when replay started
+    pub total_bytes: u64,
+    /// anything past `verified_bytes` is a damaged final record, for the reason given here.
+    /// It is cut off the WAL before the next append
+    pub stop_reason: StopReason,
+    /// segments cut from the memtable during replay, before the single merge at the end
+    pub segments_flushed: u64,
+    /// largest number of key and value bytes held in the memtable at any point during replay
+    pub peak_memory_bytes: usize,
+}
+
+impl LSMEngine {
+    /// Applies every record in `wal` to the engine. The caller must make sure `self.wal` is
+    /// not set while replaying, otherwise each record would be logged a second time.
+    ///
+    /// Instead of going through `write`, which would merge every time the memtable fills up,
+    /// replay only cuts segments and merges once at the end. The memtable is kept within
+    /// `inmemory_capacity` and the replay memory budget, so memory stays flat however long the WAL is.
+    ///
+    /// A torn final record or a final record failing its checksum ends the replay, and is cut off the WAL
+    /// before the next append so new records don't get appended after the damage. Damage with intact
+    /// records after it fails with `Error::WalCorrupted` and leaves the file as it is.
+    pub(crate) fn replay(&mut self, wal: &mut Wal) -> Result<()> {
+        let id = wal.id.clone();
+        let mut report = RecoveryReport {
+            total_bytes: wal.file.metadata().map_err(KvError::from)?.len(),
+            ..RecoveryReport::default()
+        };
+        let mut memtable_bytes = 0;
+        let mut records = wal.records(self.max_record_bytes)?;
+        for entry in records.by_ref() {
+            let entry = entry.map_err(|source| Error::WalCorrupted { wal: id.clone(), source })?;
+            report.records_replayed += 1;
+            for kv in entry.into_writes() {
+                self.replay_write(kv, &mut memtable_bytes, &mut report)?;
+            }
+        }
+        report.verified_bytes = records.verified_bytes();
+        report.stop_reason = records.stop_reason().unwrap_or_default();
+        if report.verified_bytes < report.total_bytes {
+            wal.drop_tail_from(report.verified_bytes);
+        }
+        if report.segments_flushed > 0 {
+            self.merge_segments()?;
+        }
+        //replay skips the per-write bookkeeping, so count once everything is in place
+        self.live_keys = self.count_keys_with_values()?;
+        self.recovery_report = Some(report);
+        Ok(())
+    }
+
+    /// Inserts one replayed record into the memtable, cutting a segment first if it is full
+    /// or the record would take it over the replay memory budget.
+    pub(crate) fn replay_write(&mut self, kv: KVPair, memtable_bytes: &mut usize, report: &mut RecoveryReport) -> Result<()> {
+        let (key, stored) = kv.into_entry();
+        let record_bytes = key.len() + stored.byte_len();
+        let replaced_bytes = self.memtable.get(&key).map(|old| key.len() + old.byte_len());
+
+        let over_budget = self.replay_memory_budget
+            .map(|budget| *memtable_bytes + record_bytes - replaced_bytes.unwrap_or(0) > budget)
+            .unwrap_or(false);
+        let over_capacity = replaced_bytes.is_none() && self.memtable.at_capacity();
+        if (over_budget || over_capacity) && !self.memtable.is_empty() {
+            let flushed = self.flush_memtable()?;
+            report.segments_flushed += flushed.len() as u64;
+            self.segments.extend(flushed);
+            *memtable_bytes = 0;
+        } else if let Some(replaced) = replaced_bytes {
+            *memtable_bytes -= replaced;
+        }
+
+        *memtable_bytes += record_bytes;
+        report.peak_memory_bytes = report.peak_memory_bytes.max(*memtable_bytes);
+        self.memtable.insert(key, stored);
+        Ok(())
+    }
+
+    /// Stats from the last WAL replay, `None` if the engine hasn't replayed one.
+    pub fn recovery_report(&self) -> Option<&RecoveryReport> {
+        self.recovery_report.as_ref()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{LSMBuilder, Error};
+    use crate::kv::KvError;
+    use crate::wal::StopReason;
+    use std::io::Write;
+
+    #[test]
+    fn test_replay_stays_within_budget() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        {
+            let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(100).segment_size(100).build()?;
+            for round in 0..3 {
+                for i in 0..500 {
+                    lsm.write(format!("key{:04}", i), format!("value{:04}-{}", i, round))?;
+                }
+            }
+        }
+
+        let budget = 1000;
+        let mut recovered = LSMBuilder::new()
+            .wal_path(&path)
+            .inmemory_capacity(100)
+            .segment_size(100)
+            .replay_memory_budget(budget)
+            .build()?;
+        let report = recovered.recovery_report().unwrap().clone();
+        assert_eq!(report.records_replayed, 1500);
+        assert!(report.peak_memory_bytes <= budget);
+        //each record is 7 + 11 bytes, so the budget is hit long before the capacity
+        assert!(report.segments_flushed >= 1500 * 18 / budget as u64);
+
+        for i in 0..500 {
+            assert_eq!(recovered.read(&format!("key{:04}", i))?, Some(format!("value{:04}-2", i)));
+        }
+        assert!(recovered.segments.iter().all(|s| s.size() <= 100));
+        assert_eq!(recovered.check_invariants(), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_without_budget_is_bounded_by_capacity() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        {
+            let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(1000).segment_size(1000).build()?;
+            for i in 0..300 {
+                lsm.write(format!("k{:03}", i), "v")?;
+            }
+        }
+
+        let mut recovered = LSMBuilder::new().wal_path(&path).inmemory_capacity(50).segment_size(50).build()?;
+        let report = recovered.recovery_report().unwrap().clone();
+        assert_eq!(report.segments_flushed, 5);
+        assert_eq!(report.peak_memory_bytes, 50 * 5);
+        assert_eq!(recovered.read("k000")?, Some("v".to_owned()));
+        assert_eq!(recovered.read("k299")?, Some("v".to_owned()));
+        assert!(LSMBuilder::new().build()?.recovery_report().is_none());
+        Ok(())
+    }
+
+    fn write_wal(path: &std::path::Path, records: usize) -> Result<u64, Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().wal_path(path).build()?;
+        for i in 0..records {
+            lsm.write(format!("k{}", i), format!("v{}", i))?;
+        }
+        Ok(std::fs::metadata(path)?.len())
+    }
+
+    #[test]
+    fn test_clean_eof() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let len = write_wal(&path, 5)?;
+
+        let lsm = LSMBuilder::new().wal_path(&path).build()?;
+        let report = lsm.recovery_report().unwrap();
+        assert_eq!(report.stop_reason, StopReason::CleanEof);
+        assert_eq!(report.verified_bytes, len);
+        assert_eq!(report.total_bytes, len);
+        Ok(())
+    }
+
+    #[test]
+    fn test_torn_record() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let len = write_wal(&path, 5)?;
+        let torn = b"{\"key\":\"k5\",\"val";
+        std::fs::OpenOptions::new().append(true).open(&path)?.write_all(torn)?;
+
+        {
+            let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+            let report = lsm.recovery_report().unwrap().clone();
+            assert_eq!(report.stop_reason, StopReason::TornRecord);
+            assert_eq!(report.verified_bytes, len);
+            assert_eq!(report.total_bytes, len + torn.len() as u64);
+            assert_eq!(report.records_replayed, 5);
+            //recovery itself writes nothing
+            assert_eq!(std::fs::metadata(&path)?.len(), len + torn.len() as u64);
+            lsm.write("k6", "v6")?;
+        }
+
+        //the torn bytes are gone, so writes made after recovery replay cleanly
+        let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+        assert_eq!(lsm.recovery_report().unwrap().stop_reason, StopReason::CleanEof);
+        assert_eq!(lsm.read("k6")?, Some("v6".to_owned()));
+        assert_eq!(lsm.read("k5")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_torn_newline() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let len = write_wal(&path, 3)?;
+        //a complete record whose newline never made it to disk
+        std::fs::OpenOptions::new().append(true).open(&path)?.write_all(b"{\"key\":\"k3\",\"value\":\"v3\"}")?;
+
+        let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+        let report = lsm.recovery_report().unwrap().clone();
+        assert_eq!(report.stop_reason, StopReason::TornRecord);
+        assert_eq!(report.verified_bytes, len);
+        assert_eq!(lsm.read("k3")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_torn_inside_a_character() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let len = write_wal(&path, 3)?;
+        {
+            let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+            lsm.write("k3", "ééééé")?;
+        }
+        //cut between the two bytes of the last é, dropping its newline and the rest of the record
+        let contents = std::fs::read(&path)?;
+        let cut = contents.iter().rposition(|&b| b == 0xc3).unwrap() + 1;
+        std::fs::write(&path, &contents[..cut])?;
+
+        let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+        let report = lsm.recovery_report().unwrap().clone();
+        assert_eq!(report.stop_reason, StopReason::TornRecord);
+        assert_eq!(report.verified_bytes, len);
+        assert_eq!(lsm.read("k2")?, Some("v2".to_owned()));
+        assert_eq!(lsm.read("k3")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum_mismatch() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let len = write_wal(&path, 5)?;
+        let contents = std::fs::read_to_string(&path)?;
+        let last_record = contents.find("{\"key\":\"k4\"").unwrap();
+        std::fs::write(&path, contents.replacen("\"v4\"", "\"vX\"", 1))?;
+
+        let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+        let report = lsm.recovery_report().unwrap().clone();
+        assert_eq!(report.stop_reason, StopReason::ChecksumMismatch);
+        assert_eq!(report.verified_bytes, last_record as u64);
+        assert_eq!(report.total_bytes, len);
+        assert_eq!(report.records_replayed, 4);
+        assert_eq!(lsm.read("k3")?, Some("v3".to_owned()));
+        assert_eq!(lsm.read("k4")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum_mismatch_mid_log_is_an_error() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        write_wal(&path, 5)?;
+        let contents = std::fs::read_to_string(&path)?;
+        let second_record = contents.find("{\"key\":\"k1\"").unwrap();
+        let corrupted = contents.replacen("\"v1\"", "\"vX\"", 1);
+        std::fs::write(&path, &corrupted)?;
+
+        match LSMBuilder::new().wal_path(&path).build() {
+            Err(Error::WalCorrupted { source: KvError::ChecksumMismatch { offset }, .. }) => assert_eq!(offset, second_record as u64),
+            _ => panic!("expected a checksum mismatch"),
+        }
+        //the records after the damage are still there to be salvaged
+        assert_eq!(std::fs::read_to_string(&path)?, corrupted);
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover_read_only_wal_with_torn_record() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        write_wal(&path, 3)?;
+        std::fs::OpenOptions::new().append(true).open(&path)?.write_all(b"{\"key\":\"k3\",\"val")?;
+
+        let mut lsm = LSMBuilder::new().build()?;
+        lsm.recover_from(std::fs::File::open(&path)?)?;
+        assert_eq!(lsm.recovery_report().unwrap().stop_reason, StopReason::TornRecord);
+        assert_eq!(lsm.read("k2")?, Some("v2".to_owned()));
+        assert_eq!(lsm.read("k3")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_corruption_mid_log_is_an_error() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        write_wal(&path, 3)?;
+        let contents = std::fs::read_to_string(&path)?;
+        std::fs::write(&path, contents.replacen("{\"key\":\"k1\"", "{\"kex\":\"k1\"", 1))?;
+
+        assert!(matches!(LSMBuilder::new().wal_path(&path).build(), Err(Error::WalCorrupted { .. })));
+        Ok(())
+    }
+}
@@ -0,0 +1,489 @@
+use std::ops::{Bound, RangeBounds};
+use std::time::Instant;
+use crate::{LSMEngine, Result, is_reserved};
+use crate::kv::{KVPair, KVFileIterator};
+use crate::sst::{self, FirstError};
+
+
+/// Key-value pairs yielded in key order by `LSMEngine::range` and `LSMEngine::scan_prefix`.
+///
+/// A malformed record ends the stream of the segment holding it, while the memtable and the other
+/// segments carry on, so the scan can skip records anywhere in the range, not just at its end.
+/// `finish` tells that apart from a complete scan.
+pub struct Scan<'a> {
+    inner: Box<dyn Iterator<Item=(String, String)> + 'a>,
+    errors: FirstError,
+}
+
+impl<'a> Scan<'a> {
+    /// Fails if the scan stopped at a malformed record rather than at the end of its range.
+    pub fn finish(self) -> Result<()> {
+        Ok(self.errors.check()?)
+    }
+}
+
+impl<'a> Iterator for Scan<'a> {
+    type Item = (String, String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+fn as_str<K: AsRef<str>>(bound: Bound<&K>) -> Bound<&str> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_ref()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_ref()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn own(bound: Bound<&str>) -> Bound<String> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.to_owned()),
+        Bound::Excluded(key) => Bound::Excluded(key.to_owned()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn borrow(bound: &Bound<String>) -> Bound<&str> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_str()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_str()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Where a scan stops: at a bound, or at the first key outside a prefix.
+#[derive(Clone)]
+enum End {
+    Bound(Bound<String>),
+    Prefix(String),
+}
+
+impl End {
+    fn admits(&self, key: &str) -> bool {
+        match self {
+            End::Bound(end) => below_end(key, borrow(end)),
+            End::Prefix(prefix) => key.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+fn above_start(key: &str, start: Bound<&str>) -> bool {
+    match start {
+        Bound::Included(start) => key >= start,
+        Bound::Excluded(start) => key > start,
+        Bound::Unbounded => true,
+    }
+}
+
+fn below_end(key: &str, end: Bound<&str>) -> bool {
+    match end {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
+    }
+}
+
+fn is_empty_range(start: Bound<&str>, end: Bound<&str>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+impl LSMEngine {
+    /// Iterates over the live key-value pairs with keys in `range`, in key order.
+    ///
+    /// The memtable and every segment are merged on the fly with the newest version of each key winning,
+    /// so a key deleted in the memtable hides an older value still sitting in a segment.
+    /// ```
+    /// # fn main() -> Result<(), lsm_engine::Error> {
+    /// let mut lsm = lsm_engine::LSMBuilder::new().build()?;
+    /// lsm.write("user:1500", "a")?;
+    /// lsm.write("user:2000", "b")?;
+    /// let users: Vec<_> = lsm.range("user:1000".."user:2000")?.collect();
+    /// assert_eq!(users, vec![("user:1500".to_owned(), "a".to_owned())]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn range<K: AsRef<str>, R: RangeBounds<K>>(&mut self, range: R) -> Result<Scan<'_>> {
+        let start = as_str(range.start_bound());
+        let end = as_str(range.end_bound());
+        if is_empty_range(start, end) {
+            return Ok(Scan { inner: Box::new(std::iter::empty()), errors: FirstError::default() });
+        }
+        self.scan(own(start), End::Bound(own(end)))
+    }
+
+    /// Iterates over the live key-value pairs whose keys start with `prefix`, in key order.
+    /// Shadowing works as in `range`.
+    pub fn scan_prefix(&mut self, prefix: &str) -> Result<Scan<'_>> {
+        self.scan(Bound::Included(prefix.to_owned()), End::Prefix(prefix.to_owned()))
+    }
+
+    /// Deletes every live key in `range` and returns how many there were. The keys are found with a
+    /// scan and their tombstones logged as a single WAL record, the same way `delete_many` does it.
+    pub fn delete_range<K: AsRef<str>, R: RangeBounds<K>>(&mut self, range: R) -> Result<u64> {
+        let mut scan = self.range(range)?;
+        let keys: Vec<String> = scan.by_ref().map(|(key, _)| key).collect();
+        scan.finish()?;
+        self.delete_many(keys)
+    }
+
+    /// The smallest live key and its value.
+    pub fn first(&mut self) -> Result<Option<(String, String)>> {
+        let mut scan = self.range::<String, _>(..)?;
+        let first = scan.next();
+        scan.finish()?;
+        Ok(first)
+    }
+
+    /// The largest live key and its value. Scans from the last key in the sparse index to the end,
+    /// and only if everything there is deleted steps back to the previous indexed key, so usually
+    /// just the tail of the store is read.
+    pub fn last(&mut self) -> Result<Option<(String, String)>> {
+        let mut end = Bound::Unbounded;
+        loop {
+            let start = self.sparse_memory_index
+                .range::<str, _>((Bound::Unbounded, borrow(&end)))
+                .next_back()
+                .map(|(key, _)| key.clone());
+            let lower = start.clone().map_or(Bound::Unbounded, Bound::Included);
+            let mut scan = self.scan(lower, End::Bound(end))?;
+            let found = scan.by_ref().last();
+            scan.finish()?;
+            match start {
+                Some(start) if found.is_none() => end = Bound::Excluded(start),
+                _ => return Ok(found),
+            }
+        }
+    }
+
+    /// Removes the smallest live key and returns it with its value. The delete is in the WAL
+    /// before this returns, so the entry can't be popped again, even after recovery.
+    pub fn pop_first(&mut self) -> Result<Option<(String, String)>> {
+        let first = self.first()?;
+        if let Some((key, _)) = &first {
+            self.delete(key.as_str())?;
+        }
+        Ok(first)
+    }
+
+    /// Removes the largest live key and returns it with its value, like `pop_first`.
+    pub fn pop_last(&mut self) -> Result<Option<(String, String)>> {
+        let last = self.last()?;
+        if let Some((key, _)) = &last {
+            self.delete(key.as_str())?;
+        }
+        Ok(last)
+    }
+
+    /// Iterates over every live key in sorted order. Segments are streamed rather than loaded,
+    /// so this is fine to run over a store that doesn't fit in memory. A malformed record comes
+    /// out as an error as soon as it is hit, and nothing follows it.
+    pub fn keys(&mut self) -> Result<impl Iterator<Item=Result<String>> + '_> {
+        let Scan { mut inner, errors } = self.range::<String, _>(..)?;
+        let mut done = false;
+        Ok(std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let next = inner.next();
+            //the key just read may come after records the malformed one hid, so it's dropped too
+            if let Err(e) = errors.check() {
+                done = true;
+                return Some(Err(e.into()));
+            }
+            done = next.is_none();
+            next.map(|(key, _)| Ok(key))
+        }))
+    }
+
+    /// Iterates over every live key-value pair in ascending key order, with the newest value
+    /// winning where a key appears more than once. Like `keys`, this streams the segments.
+    pub fn iter(&mut self) -> Result<Scan<'_>> {
+        self.range::<String, _>(..)
+    }
+
+    fn scan(&mut self, start: Bound<String>, end: End) -> Result<Scan<'_>> {
+        let errors = FirstError::default();
+        let inner = self.newest_records(start, end, &errors)?
+            .filter(|kv| !is_reserved(&kv.key))
+            .filter_map(|kv| {
+                let (key, stored) = kv.into_entry();
+                let value = stored.live()?.to_owned();
+                Some((key, value))
+            });
+        Ok(Scan { inner: Box::new(inner), errors })
+    }
+
+    /// Number of keys whose newest record holds a value, expired or not, which is what `len` counts.
+    pub(crate) fn count_keys_with_values(&mut self) -> Result<u64> {
+        let errors = FirstError::default();
+        let count = self.newest_records(Bound::Unbounded, End::Bound(Bound::Unbounded), &errors)?
+            .filter(|kv| kv.value.is_some() && !is_reserved(&kv.key))
+            .count() as u64;
+        errors.check()?;
+        Ok(count)
+    }
+
+    /// The newest record of every key in the range, deletes and internal records included.
+    /// Segment streams end at a malformed record, which is left in `errors`.
+    fn newest_records(&mut self, start: Bound<String>, end: End, errors: &FirstError) -> Result<impl Iterator<Item=KVPair> + '_> {
+        //only the segment the sparse index points into can start part-way through
+        let index_entry = match &start {
+            Bound::Included(key) | Bound::Excluded(key) => self.closest_index_entry(key),
+            Bound::Unbounded => None,
+        };
+
+        let mut sources: Vec<(Box<dyn Iterator<Item=KVPair> + '_>, Instant)> = vec![];
+        for (index, segment) in self.segments.iter_mut().enumerate() {
+            let overlaps = segment.key_range()
+                .map(|(first, last)| above_start(last, borrow(&start)) && (!above_start(first, borrow(&start)) || end.admits(first)))
+                .unwrap_or(false);
+            if !overlaps {
+                continue;
+            }
+            let offset = match index_entry {
+                Some((offset, segment_index)) if segment_index == index => offset,
+                _ => 0,
+            };
+            segment.seek(offset)?;
+            let timestamp = segment.timestamp();
+            let (lower, end) = (start.clone(), end.clone());
+            let records = errors.stop_on_error(segment.read())
+                .skip_while(move |kv| !above_start(&kv.key, borrow(&lower)))
+                .take_while(move |kv| end.admits(&kv.key));
+            sources.push((Box::new(records), timestamp));
+        }
+        let memtable_end = end.clone();
+        let memtable = self.memtable.range::<str>((borrow(&start), Bound::Unbounded))
+            .take_while(move |(key, _)| memtable_end.admits(key))
+            .map(|(key, stored)| KVPair::from_entry(key.clone(), stored.clone()));
+        sources.push((Box::new(memtable), Instant::now()));
+
+        Ok(sst::merge_sorted(sources))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::LSMBuilder;
+    use std::ops::Bound;
+
+    fn collect(scan: crate::scan::Scan) -> Vec<String> {
+        scan.map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn test_range_bounds() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(3).segment_size(3).sparse_offset(2).build()?;
+        for i in 0..20 {
+            lsm.write(format!("k{:02}", i), format!("v{:02}", i))?;
+        }
+        assert!(lsm.segments.len() > 2);
+
+        assert_eq!(collect(lsm.range("k03".."k06")?), vec!["k03", "k04", "k05"]);
+        assert_eq!(collect(lsm.range("k03"..="k06")?), vec!["k03", "k04", "k05", "k06"]);
+        assert_eq!(collect(lsm.range("k17"..)?), vec!["k17", "k18", "k19"]);
+        assert_eq!(collect(lsm.range(.."k02")?), vec!["k00", "k01"]);
+        assert_eq!(collect(lsm.range((Bound::Excluded("k03".to_owned()), Bound::Excluded("k06".to_owned())))?), vec!["k04", "k05"]);
+        assert_eq!(lsm.range::<String, _>(..)?.count(), 20);
+        assert_eq!(lsm.range("k03a".."k04a")?.collect::<Vec<_>>(), vec![("k04".to_owned(), "v04".to_owned())]);
+        assert_eq!(lsm.range("k06".."k03")?.count(), 0);
+        assert_eq!(lsm.range("k06".."k06")?.count(), 0);
+        assert_eq!(lsm.range("x".."z")?.count(), 0);
+
+        //the engine stays usable once the scan is dropped
+        assert_eq!(lsm.read("k05")?, Some("v05".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_newest_wins() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(4).segment_size(4).build()?;
+        for i in 0..10 {
+            lsm.write(format!("k{}", i), "old")?;
+        }
+        lsm.write("k2", "new")?;
+        lsm.delete("k3")?;
+        lsm.delete("k7")?;
+        lsm.write(format!("{}internal", crate::RESERVED_KEY_PREFIX), "hidden").unwrap_err();
+        lsm.put(format!("{}internal", crate::RESERVED_KEY_PREFIX), Some("hidden".to_owned()))?;
+        assert!(lsm.memtable.get("k7").is_some());
+
+        let all: Vec<_> = lsm.range::<String, _>(..)?.collect();
+        let expected: Vec<_> = (0..10)
+            .filter(|i| *i != 3 && *i != 7)
+            .map(|i| (format!("k{}", i), if i == 2 { "new" } else { "old" }.to_owned()))
+            .collect();
+        assert_eq!(all, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keys() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(3).segment_size(3).build()?;
+        assert_eq!(lsm.keys()?.collect::<Result<Vec<_>, _>>()?.len(), 0);
+        for i in (0..10).rev() {
+            lsm.write(format!("k{}", i), "v")?;
+        }
+        lsm.write("k5", "again")?;
+        lsm.delete("k2")?;
+        lsm.delete("missing")?;
+
+        let keys: Vec<_> = lsm.keys()?.collect::<Result<_, _>>()?;
+        assert_eq!(keys, vec!["k0", "k1", "k3", "k4", "k5", "k6", "k7", "k8", "k9"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(100).segment_size(100).build()?;
+        for version in ["a", "b"].iter() {
+            for i in 0..5 {
+                lsm.memtable.insert(format!("k{}", i), Some(version.to_string()).into());
+            }
+            let flushed = lsm.flush_memtable()?;
+            lsm.segments.extend(flushed);
+        }
+        lsm.write("k1", "c")?;
+        //the segments were never indexed, so a checked delete wouldn't find k3
+        lsm.delete_unchecked("k3")?;
+
+        let pairs: Vec<_> = lsm.iter()?.collect();
+        assert_eq!(pairs, vec![
+            ("k0".to_owned(), "b".to_owned()),
+            ("k1".to_owned(), "c".to_owned()),
+            ("k2".to_owned(), "b".to_owned()),
+            ("k4".to_owned(), "b".to_owned()),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_range() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        {
+            let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(4).segment_size(4).build()?;
+            for i in 0..20 {
+                lsm.write(format!("k{:02}", i), "v")?;
+            }
+            lsm.delete("k07")?;
+            let wal_lines = std::fs::read_to_string(&path)?.lines().count();
+
+            assert_eq!(lsm.delete_range("k05".."k10")?, 4);
+            assert_eq!(std::fs::read_to_string(&path)?.lines().count(), wal_lines + 1);
+            assert_eq!(lsm.delete_range("x"..)?, 0);
+            assert_eq!(std::fs::read_to_string(&path)?.lines().count(), wal_lines + 1);
+            assert_eq!(collect(lsm.range("k03".."k12")?), vec!["k03", "k04", "k10", "k11"]);
+            assert_eq!(lsm.len(), 15);
+        }
+        let mut recovered = LSMBuilder::new().wal_path(&path).build()?;
+        assert_eq!(recovered.read("k05")?, None);
+        assert_eq!(recovered.read("k10")?, Some("v".to_owned()));
+        assert_eq!(recovered.len(), 15);
+        Ok(())
+    }
+
+    #[test]
+    fn test_first_and_last() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(4).segment_size(4).sparse_offset(3).build()?;
+        assert_eq!(lsm.first()?, None);
+        assert_eq!(lsm.last()?, None);
+        for i in 0..20 {
+            lsm.write(format!("k{:02}", i), format!("v{:02}", i))?;
+        }
+        assert_eq!(lsm.first()?, Some(("k00".to_owned(), "v00".to_owned())));
+        assert_eq!(lsm.last()?, Some(("k19".to_owned(), "v19".to_owned())));
+
+        //deleting the ends, across several index entries, moves both boundaries inward
+        for i in (0..3).chain(11..20) {
+            lsm.delete(format!("k{:02}", i))?;
+        }
+        assert_eq!(lsm.first()?, Some(("k03".to_owned(), "v03".to_owned())));
+        assert_eq!(lsm.last()?, Some(("k10".to_owned(), "v10".to_owned())));
+
+        //the memtable can hold either end
+        lsm.write("a", "first")?;
+        lsm.write("z", "last")?;
+        assert_eq!(lsm.first()?, Some(("a".to_owned(), "first".to_owned())));
+        assert_eq!(lsm.last()?, Some(("z".to_owned(), "last".to_owned())));
+        lsm.delete_range::<&str, _>(..)?;
+        assert_eq!(lsm.first()?, None);
+        assert_eq!(lsm.last()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pop() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        {
+            let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(3).segment_size(3).build()?;
+            assert_eq!(lsm.pop_first()?, None);
+            for i in 0..10 {
+                lsm.write(format!("{:04}", i), format!("job{}", i))?;
+            }
+            assert_eq!(lsm.pop_first()?, Some(("0000".to_owned(), "job0".to_owned())));
+            assert_eq!(lsm.pop_first()?, Some(("0001".to_owned(), "job1".to_owned())));
+            assert_eq!(lsm.pop_last()?, Some(("0009".to_owned(), "job9".to_owned())));
+            assert_eq!(lsm.len(), 7);
+        }
+
+        let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(3).segment_size(3).build()?;
+        let mut popped = vec![];
+        while let Some((key, _)) = lsm.pop_first()? {
+            popped.push(key);
+        }
+        assert_eq!(popped, (2..9).map(|i| format!("{:04}", i)).collect::<Vec<_>>());
+        assert_eq!(lsm.pop_last()?, None);
+        assert!(lsm.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_prefix() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(4).segment_size(4).sparse_offset(3).build()?;
+        for i in 0..10 {
+            lsm.write(format!("account:{}", i), "a")?;
+            lsm.write(format!("session:{}", i), "old")?;
+            lsm.write(format!("sessions:{}", i), "s")?;
+        }
+        lsm.write("session:4", "new")?;
+        lsm.delete("session:7")?;
+        assert!(lsm.memtable.get("session:4").is_some());
+
+        let sessions: Vec<_> = lsm.scan_prefix("session:")?.collect();
+        let expected: Vec<_> = (0..10)
+            .filter(|i| *i != 7)
+            .map(|i| (format!("session:{}", i), if i == 4 { "new" } else { "old" }.to_owned()))
+            .collect();
+        assert_eq!(sessions, expected);
+        assert_eq!(lsm.scan_prefix("sessions:")?.count(), 10);
+        assert_eq!(lsm.scan_prefix("")?.count(), 29);
+        assert_eq!(lsm.scan_prefix("nothing")?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_over_unmerged_segments() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(100).segment_size(100).build()?;
+        for (round, value) in ["a", "b", "c"].iter().enumerate() {
+            for i in round..6 {
+                lsm.memtable.insert(format!("k{}", i), Some(value.to_string()).into());
+            }
+            let flushed = lsm.flush_memtable()?;
+            lsm.segments.extend(flushed);
+        }
+        let all: Vec<_> = lsm.range("k0"..="k5")?.map(|(_, value)| value).collect();
+        assert_eq!(all, vec!["a", "b", "c", "c", "c", "c"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt;
+use crate::{LSMEngine, Result};
+use crate::kv::KvError;
+
+
+/// Operations served since the engine was built. Records replayed from the WAL are not counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct OpCounts {
+    pub reads: u64,
+    pub writes: u64,
+    pub deletes: u64,
+}
+
+/// A snapshot of the engine's internals, for logging and for figuring out why it behaves as it does.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub segments: usize,
+    /// records in each segment, oldest segment first
+    pub entries_per_segment: Vec<u64>,
+    pub memtable_entries: usize,
+    pub memtable_capacity: usize,
+    pub sparse_index_entries: usize,
+    /// size of the WAL file, `None` if the engine has no WAL
+    pub wal_bytes: Option<u64>,
+    /// calls to `read` since startup
+    pub reads: u64,
+    /// keys written since startup, through any API, batches included
+    pub writes: u64,
+    /// keys deleted since startup, through any API, batches included
+    pub deletes: u64,
+}
+
+impl LSMEngine {
+    /// Collects `Stats`. Only the WAL's file size is fetched from the filesystem.
+    pub fn stats(&self) -> Result<Stats> {
+        let wal_bytes = match self.wal.as_ref() {
+            Some(wal) => Some(wal.file.metadata().map_err(KvError::from)?.len()),
+            None => None,
+        };
+        Ok(Stats {
+            segments: self.segments.len(),
+            entries_per_segment: self.segments.iter().map(|segment| segment.size()).collect(),
+            memtable_entries: self.memtable.len(),
+            memtable_capacity: self.memtable.capacity(),
+            sparse_index_entries: self.sparse_memory_index.len(),
+            wal_bytes,
+            reads: self.op_counts.reads,
+            writes: self.op_counts.writes,
+            deletes: self.op_counts.deletes,
+        })
+    }
+}
+
+impl LSMEngine {
+    /// Roughly how many bytes the engine takes up: the segment files and the WAL on disk, plus the
+    /// keys and values held in the memtable. Every figure is kept up to date as data is written,
+    /// so this is cheap to call, e.g. to enforce a disk budget before each write.
+    pub fn approximate_size_bytes(&self) -> u64 {
+        let segments: u64 = self.segments.iter().map(|segment| segment.stats().total_bytes).sum();
+        let wal = self.wal.as_ref().map(|wal| wal.len()).unwrap_or(0);
+        segments + wal + self.memtable.bytes() as u64
+    }
+}
+
+impl fmt::Debug for LSMEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LSMEngine")
+            .field("segments", &self.segments.len())
+            .field("memtable_entries", &self.memtable.len())
+            .field("live_keys", &self.live_keys)
+            .field("wal", &self.wal.as_ref().map(|wal| &wal.id))
+            .finish_non_exhaustive()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{LSMBuilder, WriteBatch};
+
+    #[test]
+    fn test_stats() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut lsm = LSMBuilder::new().wal_path(dir.path().join("wal")).inmemory_capacity(3).segment_size(2).sparse_offset(2).build()?;
+        let empty = lsm.stats()?;
+        assert_eq!((empty.segments, empty.memtable_entries, empty.memtable_capacity), (0, 0, 3));
+        let header_bytes = empty.wal_bytes.unwrap();
+
+        for i in 0..5 {
+            lsm.write(format!("k{}", i), "v")?;
+        }
+        lsm.delete("k0")?;
+        lsm.delete_many(vec!["k1".to_owned(), "k2".to_owned()])?;
+        let mut batch = WriteBatch::new();
+        batch.put("k5", "v");
+        batch.delete("k3");
+        lsm.write_batch(batch)?;
+        lsm.read("k4")?;
+        lsm.read("missing")?;
+
+        let stats = lsm.stats()?;
+        assert_eq!((stats.reads, stats.writes, stats.deletes), (2, 6, 4));
+        //k0 to k4 merged into segments, deletes included, with only k5 left in the memtable
+        assert_eq!(stats.segments, 3);
+        assert_eq!(stats.entries_per_segment, vec![2, 2, 1]);
+        assert_eq!(stats.memtable_entries, 1);
+        assert_eq!(stats.sparse_index_entries, 3);
+        assert!(stats.wal_bytes.unwrap() > header_bytes);
+        assert!(format!("{:?}", lsm).starts_with("LSMEngine { segments: "));
+
+        //replay doesn't count as new operations
+        drop(lsm);
+        let lsm = LSMBuilder::new().wal_path(dir.path().join("wal")).build()?;
+        let stats = lsm.stats()?;
+        assert_eq!((stats.reads, stats.writes, stats.deletes), (0, 0, 0));
+        assert_eq!(LSMBuilder::new().build()?.stats()?.wal_bytes, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_approximate_size_bytes() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let mut lsm = LSMBuilder::new().wal_path(&path).inmemory_capacity(10).segment_size(10).build()?;
+        let mut previous = lsm.approximate_size_bytes();
+        assert_eq!(previous, std::fs::metadata(&path)?.len());
+        for i in 0..25 {
+            lsm.write(format!("k{:02}", i), "value")?;
+            let size = lsm.approximate_size_bytes();
+            assert!(size > previous);
+            previous = size;
+        }
+        let segment_bytes: u64 = lsm.segment_stats().iter().map(|s| s.total_bytes).sum();
+        assert_eq!(lsm.approximate_size_bytes(), segment_bytes + std::fs::metadata(&path)?.len() + 5 * (3 + 5));
+
+        //overwriting shrinks the segments once merged, though the WAL keeps growing
+        let segments_before = segment_bytes;
+        for i in 0..20 {
+            lsm.write(format!("k{:02}", i), "v")?;
+        }
+        lsm.flush()?;
+        let segment_bytes: u64 = lsm.segment_stats().iter().map(|s| s.total_bytes).sum();
+        assert!(segment_bytes < segments_before + 5 * 20);
+        assert_eq!(lsm.approximate_size_bytes(), segment_bytes + std::fs::metadata(&path)?.len());
+        lsm.clear()?;
+        assert_eq!(lsm.approximate_size_bytes(), std::fs::metadata(&path)?.len());
+        Ok(())
+    }
+}
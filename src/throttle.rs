@@ -0,0 +1,113 @@
+use std::time::{Duration, Instant};
+
+/// A token bucket used to pace compaction IO. Every byte read or written by a merge
+/// has to be paid for; when the bucket runs dry the merge sleeps until it refills.
+pub struct RateLimiter {
+    bytes_per_sec: Option<u64>,
+    burst: Option<u64>,
+    available: f64,
+    last_refill: Instant,
+    merge_bytes: u64,
+    merge_started: Option<Instant>,
+    throughput: f64,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: Option<u64>, burst: Option<u64>) -> Self {
+        let mut limiter = RateLimiter {
+            bytes_per_sec: None,
+            burst,
+            available: 0.0,
+            last_refill: Instant::now(),
+            merge_bytes: 0,
+            merge_started: None,
+            throughput: 0.0,
+        };
+        limiter.set_rate(bytes_per_sec);
+        limiter
+    }
+
+    /// Changes the limit; `None` disables pacing. The bucket starts out full.
+    pub fn set_rate(&mut self, bytes_per_sec: Option<u64>) {
+        self.bytes_per_sec = bytes_per_sec.filter(|rate| *rate > 0);
+        self.available = self.capacity();
+        self.last_refill = Instant::now();
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bytes_per_sec
+    }
+
+    /// How many bytes can go through without pausing. Defaults to one second's worth.
+    fn capacity(&self) -> f64 {
+        self.burst.or(self.bytes_per_sec).unwrap_or(0) as f64
+    }
+
+    fn refill(&mut self, rate: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.available = (self.available + elapsed * rate as f64).min(self.capacity());
+        self.last_refill = now;
+    }
+
+    /// Marks the start of a merge, used to compute its throughput.
+    pub fn begin(&mut self) {
+        self.merge_bytes = 0;
+        self.merge_started = Some(Instant::now());
+    }
+
+    /// Accounts for `bytes` of compaction IO, blocking if the merge is ahead of its budget.
+    pub fn acquire(&mut self, bytes: u64) {
+        self.merge_bytes += bytes;
+        if let Some(rate) = self.bytes_per_sec {
+            self.refill(rate);
+            self.available -= bytes as f64;
+            if self.available < 0.0 {
+                std::thread::sleep(Duration::from_secs_f64(-self.available / rate as f64));
+                self.refill(rate);
+            }
+        }
+    }
+
+    pub fn end(&mut self) {
+        if let Some(started) = self.merge_started.take() {
+            let elapsed = started.elapsed().as_secs_f64();
+            self.throughput = if elapsed > 0.0 { self.merge_bytes as f64 / elapsed } else { 0.0 };
+        }
+    }
+
+    /// Bytes per second moved by the most recent merge.
+    pub fn throughput(&self) -> f64 {
+        self.throughput
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlimited_never_sleeps() {
+        let mut limiter = RateLimiter::new(None, None);
+        let start = Instant::now();
+        limiter.acquire(u64::MAX / 2);
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_burst_then_paced() {
+        let mut limiter = RateLimiter::new(Some(10_000), Some(1_000));
+        let start = Instant::now();
+        limiter.acquire(1_000);
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        //another 2000 bytes at 10k/s needs ~200ms
+        limiter.begin();
+        limiter.acquire(1_000);
+        limiter.acquire(1_000);
+        limiter.end();
+        assert!(start.elapsed() >= Duration::from_millis(190));
+        assert!(limiter.throughput() < 12_000.0);
+    }
+}
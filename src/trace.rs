@@ -0,0 +1,180 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand::distributions::Alphanumeric;
+use crate::{LSMEngine, LSMBuilder, Result};
+use crate::kv::KvError;
+
+
+/// One line of a trace file. Values are never recorded, only their length.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum TraceRecord {
+    /// first line of every trace; replay regenerates values from this seed
+    Start { filler_seed: u64 },
+    Write { key: String, value_len: usize, at_micros: u64 },
+    Read { key: String, at_micros: u64 },
+    Delete { key: String, at_micros: u64 },
+}
+
+pub(crate) struct Tracer {
+    file: File,
+    started: Instant,
+}
+
+impl Tracer {
+    pub(crate) fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new().write(true).create(true).truncate(true).open(path).map_err(KvError::from)?;
+        let mut tracer = Tracer { file, started: Instant::now() };
+        tracer.append(&TraceRecord::Start { filler_seed: rand::thread_rng().gen() })?;
+        Ok(tracer)
+    }
+
+    fn at_micros(&self) -> u64 {
+        self.started.elapsed().as_micros() as u64
+    }
+
+    fn append(&mut self, record: &TraceRecord) -> Result<()> {
+        serde_json::to_writer(&mut self.file, record).map_err(KvError::from)?;
+        self.file.write_all(b"\n").map_err(KvError::from)?;
+        Ok(())
+    }
+
+    pub(crate) fn write(&mut self, key: &str, value_len: usize) -> Result<()> {
+        let at_micros = self.at_micros();
+        self.append(&TraceRecord::Write { key: key.to_owned(), value_len, at_micros })
+    }
+
+    pub(crate) fn read(&mut self, key: &str) -> Result<()> {
+        let at_micros = self.at_micros();
+        self.append(&TraceRecord::Read { key: key.to_owned(), at_micros })
+    }
+
+    pub(crate) fn delete(&mut self, key: &str) -> Result<()> {
+        let at_micros = self.at_micros();
+        self.append(&TraceRecord::Delete { key: key.to_owned(), at_micros })
+    }
+}
+
+/// Outcome of replaying a trace with `replay_trace`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayReport {
+    pub operations: u64,
+    pub elapsed: Duration,
+    pub operations_per_sec: f64,
+    pub p50_latency: Duration,
+    pub p99_latency: Duration,
+    pub max_latency: Duration,
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::from_secs(0);
+    }
+    let rank = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[rank]
+}
+
+/// Runs the workload recorded by `LSMBuilder::trace_to` against a fresh engine built from `builder`.
+///
+/// Values are regenerated from the trace's filler seed at their recorded lengths, so two replays
+/// of the same trace write identical data. With `preserve_timing`, operations wait for their
+/// recorded offset from the start of the trace instead of running back to back.
+pub fn replay_trace<P: AsRef<Path>>(path: P, builder: LSMBuilder, preserve_timing: bool) -> Result<ReplayReport> {
+    let mut lsm = builder.build()?;
+    let reader = BufReader::new(File::open(path).map_err(KvError::from)?);
+    let mut filler = StdRng::seed_from_u64(0);
+    let mut latencies = vec![];
+    let started = Instant::now();
+
+    for line in reader.lines() {
+        let record: TraceRecord = serde_json::from_str(&line.map_err(KvError::from)?).map_err(KvError::from)?;
+        let at_micros = match &record {
+            TraceRecord::Start { filler_seed } => {
+                filler = StdRng::seed_from_u64(*filler_seed);
+                continue;
+            }
+            TraceRecord::Write { at_micros, .. } | TraceRecord::Read { at_micros, .. } | TraceRecord::Delete { at_micros, .. } => *at_micros,
+        };
+        if preserve_timing {
+            let due = Duration::from_micros(at_micros);
+            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+
+        let op_started = Instant::now();
+        apply(&mut lsm, record, &mut filler)?;
+        latencies.push(op_started.elapsed());
+    }
+
+    let elapsed = started.elapsed();
+    latencies.sort();
+    Ok(ReplayReport {
+        operations: latencies.len() as u64,
+        elapsed,
+        operations_per_sec: latencies.len() as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+        p50_latency: percentile(&latencies, 0.5),
+        p99_latency: percentile(&latencies, 0.99),
+        max_latency: latencies.last().copied().unwrap_or_default(),
+    })
+}
+
+fn apply(lsm: &mut LSMEngine, record: TraceRecord, filler: &mut StdRng) -> Result<()> {
+    match record {
+        TraceRecord::Write { key, value_len, .. } => {
+            let value: String = filler.sample_iter(&Alphanumeric).take(value_len).collect();
+            lsm.write(key, value)
+        }
+        TraceRecord::Read { key, .. } => lsm.read(&key).map(|_| ()),
+        TraceRecord::Delete { key, .. } => lsm.delete(key).map(|_| ()),
+        TraceRecord::Start { .. } => Ok(()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{LSMBuilder, replay_trace};
+    use crate::trace::TraceRecord;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn test_trace_and_replay() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("trace");
+        {
+            let mut lsm = LSMBuilder::new().inmemory_capacity(4).segment_size(4).trace_to(&path).build()?;
+            for i in 0..10 {
+                lsm.write(format!("k{}", i), "secret value")?;
+            }
+            lsm.delete("k3")?;
+            lsm.read("k3")?;
+            lsm.contains("k4")?;
+        }
+
+        let records = BufReader::new(std::fs::File::open(&path)?)
+            .lines()
+            .map(|line| Ok(serde_json::from_str::<TraceRecord>(&line?)?))
+            .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+        assert_eq!(records.len(), 1 + 10 + 3);
+        assert!(matches!(records[0], TraceRecord::Start { .. }));
+        assert!(matches!(&records[1], TraceRecord::Write { key, value_len: 12, .. } if key == "k0"));
+        assert!(matches!(&records[11], TraceRecord::Delete { key, .. } if key == "k3"));
+        assert!(matches!(&records[13], TraceRecord::Read { key, .. } if key == "k4"));
+        assert!(!std::fs::read_to_string(&path)?.contains("secret"));
+
+        let report = replay_trace(&path, LSMBuilder::new().inmemory_capacity(2).segment_size(2), false)?;
+        assert_eq!(report.operations, 13);
+        assert!(report.p50_latency <= report.p99_latency);
+        assert!(report.p99_latency <= report.max_latency);
+
+        let timed = replay_trace(&path, LSMBuilder::new(), true)?;
+        assert_eq!(timed.operations, 13);
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{LSMEngine, Result, is_reserved};
+use crate::kv::KVFileIterator;
+use crate::wal::WalEntry;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+
+/// Limits on how long a warm-up may run, since it competes with real traffic.
+#[derive(Default, Clone)]
+pub struct WarmupOptions {
+    deadline: Option<Instant>,
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl WarmupOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop reading keys once `deadline` has passed.
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Stop reading keys as soon as `flag` is set, e.g. from another thread.
+    pub fn cancel_flag(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(flag);
+        self
+    }
+
+    fn should_stop(&self) -> bool {
+        let past_deadline = self.deadline.map(|deadline| Instant::now() >= deadline).unwrap_or(false);
+        past_deadline || self.cancel.as_ref().map(|flag| flag.load(Ordering::Relaxed)).unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WarmupReport {
+    /// keys that were read before the warm-up finished or was interrupted
+    pub keys_read: usize,
+    pub keys_found: usize,
+    /// key and value bytes of the entries that were found
+    pub bytes_touched: u64,
+    pub elapsed: Duration,
+    pub interrupted: bool,
+}
+
+impl LSMEngine {
+    /// Reads `keys` so that the segment pages they live on are pulled into the OS page cache.
+    pub fn warm_up(&mut self, keys: impl IntoIterator<Item=String>) -> Result<WarmupReport> {
+        self.warm_up_with(keys, &WarmupOptions::new())
+    }
+
+    pub fn warm_up_with(&mut self, keys: impl IntoIterator<Item=String>, options: &WarmupOptions) -> Result<WarmupReport> {
+        let start = Instant::now();
+        let mut report = WarmupReport {
+            keys_read: 0,
+            keys_found: 0,
+            bytes_touched: 0,
+            elapsed: Duration::default(),
+            interrupted: false,
+        };
+        for key in keys {
+            if options.should_stop() {
+                report.interrupted = true;
+                break;
+            }
+            report.keys_read += 1;
+            if let Some(value) = self.read(&key)? {
+                report.keys_found += 1;
+                report.bytes_touched += (key.len() + value.len()) as u64;
+            }
+        }
+        report.elapsed = start.elapsed();
+        Ok(report)
+    }
+
+    /// Warms up the `n` most recently written distinct keys recorded in the WAL.
+    /// Does nothing if the engine has no WAL.
+    pub fn warm_up_recent(&mut self, n: usize, options: &WarmupOptions) -> Result<WarmupReport> {
+        let keys = self.recent_wal_keys(n)?;
+        self.warm_up_with(keys, options)
+    }
+
+    fn recent_wal_keys(&mut self, n: usize) -> Result<Vec<String>> {
+        let wal = match self.wal.as_mut() {
+            Some(wal) => wal,
+            None => return Ok(vec![]),
+        };
+        let end = wal.tell()?;
+        let mut last_written = HashMap::new();
+        let mut position = 0;
+        for entry in wal.records(self.max_record_bytes)? {
+            let keys = match entry? {
+                WalEntry::Put(kv) => vec![kv.key],
+                WalEntry::DeleteMany(keys) => keys,
+                WalEntry::WriteBatch(writes) => writes.into_iter().map(|(key, _)| key).collect(),
+            };
+            for key in keys.into_iter().filter(|key| !is_reserved(key)) {
+                last_written.insert(key, position);
+                position += 1;
+            }
+        }
+        //new records are appended wherever the cursor is, so put it back at the end
+        wal.seek(end)?;
+
+        let mut keys: Vec<_> = last_written.into_iter().collect();
+        keys.sort_by_key(|(_, position)| Reverse(*position));
+        Ok(keys.into_iter().take(n).map(|(key, _)| key).collect())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::LSMBuilder;
+    use crate::warmup::WarmupOptions;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Instant;
+
+    #[test]
+    fn test_warm_up() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().inmemory_capacity(2).segment_size(4).build()?;
+        for i in 0..10 {
+            lsm.write(format!("k{}", i), format!("v{}", i))?;
+        }
+        let report = lsm.warm_up(vec!["k1".to_owned(), "k9".to_owned(), "missing".to_owned()])?;
+        assert_eq!(report.keys_read, 3);
+        assert_eq!(report.keys_found, 2);
+        assert_eq!(report.bytes_touched, 8);
+        assert!(!report.interrupted);
+        Ok(())
+    }
+
+    #[test]
+    fn test_warm_up_is_interruptible() -> Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().build()?;
+        lsm.write("k1", "v1")?;
+
+        let report = lsm.warm_up_with(vec!["k1".to_owned()], &WarmupOptions::new().deadline(Instant::now()))?;
+        assert!(report.interrupted);
+        assert_eq!(report.keys_read, 0);
+
+        let cancelled = Arc::new(AtomicBool::new(true));
+        let report = lsm.warm_up_with(vec!["k1".to_owned()], &WarmupOptions::new().cancel_flag(cancelled))?;
+        assert!(report.interrupted);
+        Ok(())
+    }
+
+    #[test]
+    fn test_warm_up_recent_wal_keys() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let mut lsm = LSMBuilder::new().wal_path(&path).build()?;
+        for &key in &["k1", "k2", "k3", "k1"] {
+            lsm.write(key, "v")?;
+        }
+        assert_eq!(lsm.recent_wal_keys(2)?, vec!["k1".to_owned(), "k3".to_owned()]);
+
+        let report = lsm.warm_up_recent(10, &WarmupOptions::new())?;
+        assert_eq!(report.keys_found, 3);
+
+        //reading the WAL must not disturb where new records go
+        lsm.write("k4", "v")?;
+        let mut recovered = LSMBuilder::new().wal_path(&path).build()?;
+        for &key in &["k1", "k2", "k3", "k4"] {
+            assert_eq!(recovered.read(key)?, Some("v".to_owned()));
+        }
+        Ok(())
+    }
+}